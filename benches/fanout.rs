@@ -0,0 +1,198 @@
+//Fans broadcasts out to thousands of users, run with cargo bench --bench fanout
+use roommate::prelude::*;
+use roommate::protocol::{self, Frame};
+use std::time::{Duration, Instant};
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver},
+        RwLock,
+    },
+    task::JoinHandle,
+};
+use uuid::Uuid;
+
+const USERS: usize = 5000;
+const BROADCASTS: usize = 200;
+
+//Users joining while the broadcasts go out, in the cases with joins
+const JOINS: usize = 5000;
+
+//A ring room should take as long to emit to the most users as to the fewest
+const RING_USERS: [usize; 3] = [1000, 5000, 20000];
+
+type Receivers = Vec<UnboundedReceiver<protocol::User>>;
+
+//How rooms fanned out before they ran as actors: every broadcast reads the members behind a lock.
+//It sends the same shared frame as the actor, so only the way to the members differs
+#[derive(Default)]
+struct LockRoom {
+    user_senders: RwLock<HashMap<Uuid, UnboundedSender<protocol::User>>>,
+}
+
+impl LockRoom {
+    async fn broadcast(&self, event: &str, payload: serde_json::Value) {
+        let frame = Frame::encode(protocol::User::Event(event.to_string(), payload));
+        let command = protocol::User::Shared(frame);
+
+        for sender in self.user_senders.read().await.values() {
+            let _ = sender.send(command.clone());
+        }
+    }
+}
+
+//Joins users one at a time until there are this many more, the way a crowd arrives during a broadcast
+fn join_lock_room(room: Arc<LockRoom>, joins: usize) -> JoinHandle<Receivers> {
+    tokio::spawn(async move {
+        let mut receivers = Receivers::new();
+
+        for _ in 0..joins {
+            let (sender, receiver) = unbounded_channel();
+            room.user_senders
+                .write()
+                .await
+                .insert(Uuid::new_v4(), sender);
+            receivers.push(receiver);
+            tokio::task::yield_now().await;
+        }
+
+        receivers
+    })
+}
+
+fn join_actor_room(room: Arc<Room>, joins: usize) -> JoinHandle<Receivers> {
+    tokio::spawn(async move {
+        let mut receivers = Receivers::new();
+
+        for _ in 0..joins {
+            let (sender, receiver) = unbounded_channel();
+            let _ = room
+                .sender
+                .send(protocol::Room::ConnectUser(Uuid::new_v4(), sender));
+            receivers.push(receiver);
+            tokio::task::yield_now().await;
+        }
+
+        receivers
+    })
+}
+
+async fn lock_room(joins: usize) -> Duration {
+    let room = Arc::new(LockRoom::default());
+    let mut receivers = Receivers::new();
+
+    for _ in 0..USERS {
+        let (sender, receiver) = unbounded_channel();
+        room.user_senders
+            .write()
+            .await
+            .insert(Uuid::new_v4(), sender);
+        receivers.push(receiver);
+    }
+
+    let joining = join_lock_room(room.clone(), joins);
+    let start = Instant::now();
+
+    for round in 0..BROADCASTS {
+        room.broadcast("message", json!({ "round": round, "text": "hello" }))
+            .await;
+    }
+
+    let elapsed = start.elapsed();
+    check(receivers, BROADCASTS);
+    joining.await.unwrap();
+    elapsed
+}
+
+async fn actor_room(users: usize, fan_out: FanOut, joins: usize) -> Duration {
+    let (room, actor) = RoomBuilder::new()
+        .namespace("bench")
+        .fan_out(fan_out)
+        .build();
+    let handle = room.run(actor);
+    let mut receivers = Receivers::new();
+
+    for _ in 0..users {
+        let (sender, receiver) = unbounded_channel();
+        let _ = room
+            .sender
+            .send(protocol::Room::ConnectUser(Uuid::new_v4(), sender));
+        receivers.push(receiver);
+    }
+
+    assert_eq!(room.members().await.unwrap().len(), users);
+    let joining = join_actor_room(room.clone(), joins);
+    let start = Instant::now();
+
+    for round in 0..BROADCASTS {
+        room.broadcast("message", json!({ "round": round, "text": "hello" }))
+            .await;
+    }
+
+    //The run loop answers in order, so every broadcast went out once this returns
+    room.members().await.unwrap();
+
+    let elapsed = start.elapsed();
+    joining.await.unwrap();
+    let _ = room.sender.send(protocol::Room::Close);
+    let _ = handle.await;
+
+    //In a ring room the channel of a user only carries the subscription to the ring
+    match fan_out {
+        FanOut::PerUser => check(receivers, BROADCASTS),
+        FanOut::Ring { .. } => check(receivers, 1),
+    }
+
+    elapsed
+}
+
+fn check(mut receivers: Receivers, expected: usize) {
+    for receiver in &mut receivers {
+        let mut received = 0;
+
+        while receiver.try_recv().is_ok() {
+            received += 1;
+        }
+
+        assert_eq!(received, expected);
+    }
+}
+
+fn report(name: &str, users: usize, elapsed: Duration) {
+    let deliveries = (users * BROADCASTS) as f64;
+
+    println!(
+        "{:<12} {:>5} users {:>4} broadcasts {:>9.2?} {:>9.2?} per broadcast {:>12.0} deliveries/s",
+        name,
+        users,
+        BROADCASTS,
+        elapsed,
+        elapsed / BROADCASTS as u32,
+        deliveries / elapsed.as_secs_f64()
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    report("lock", USERS, lock_room(0).await);
+    report("actor", USERS, actor_room(USERS, FanOut::PerUser, 0).await);
+
+    //The joins take the write lock of the lock room, in the actor room they queue with the broadcasts
+    report("lock+joins", USERS, lock_room(JOINS).await);
+    let joined = actor_room(USERS, FanOut::PerUser, JOINS).await;
+    report("actor+joins", USERS, joined);
+
+    let ring = FanOut::Ring {
+        capacity: BROADCASTS,
+        lag: LagPolicy::Skip,
+    };
+
+    //A ring room is only timed putting the events in the ring, its users read them afterwards
+    for users in RING_USERS {
+        report(
+            "per-user",
+            users,
+            actor_room(users, FanOut::PerUser, 0).await,
+        );
+        report("ring", users, actor_room(users, ring, 0).await);
+    }
+}
@@ -0,0 +1,375 @@
+use crate::{
+    error::{Error, Result},
+    protocol::HISTORY_EVENT,
+};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+    time,
+};
+use tokio_tungstenite::connect_async;
+use tracing::{debug, warn};
+use tungstenite::Message;
+
+type Handler = Arc<dyn Fn(Value) + Send + Sync>;
+
+#[derive(Default)]
+struct Subscriptions {
+    handlers: HashMap<String, Vec<Handler>>,
+
+    //Requests waiting for their answer, by request id
+    waiters: HashMap<u64, Waiter>,
+    next_request: u64,
+}
+
+struct Waiter {
+    reply_event: String,
+    answer: oneshot::Sender<Result<Value>>,
+}
+
+impl Subscriptions {
+    //The request an answer belongs to, the oldest one waiting for the event when the answer has no id
+    fn waiter(&mut self, event: &str, request_id: Option<u64>) -> Option<Waiter> {
+        let request_id = match request_id {
+            Some(request_id) => request_id,
+            None if event == "error" => return None,
+            None => *self
+                .waiters
+                .iter()
+                .filter(|(_, waiter)| waiter.reply_event == event)
+                .map(|(request_id, _)| request_id)
+                .min()?,
+        };
+
+        match self.waiters.get(&request_id) {
+            Some(waiter) if event == "error" || waiter.reply_event == event => {
+                self.waiters.remove(&request_id)
+            }
+            _ => None,
+        }
+    }
+}
+
+//A client of a roommate server speaking the same frames a browser would
+pub struct Client {
+    outgoing: UnboundedSender<Message>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    task: JoinHandle<()>,
+}
+
+impl Client {
+    pub async fn connect(url: &str) -> Result<Self> {
+        let (stream, _) = connect_async(url).await?;
+        let (outgoing, outgoing_receiver) = unbounded_channel::<Message>();
+        let subscriptions = Arc::new(Mutex::new(Subscriptions::default()));
+
+        let task = tokio::spawn(Self::run(stream, outgoing_receiver, subscriptions.clone()));
+
+        Ok(Self {
+            outgoing,
+            subscriptions,
+            task,
+        })
+    }
+
+    pub fn join(&self, room: &str) -> Result<()> {
+        self.send(json!({"event": "connect", "room": room}))
+    }
+
+    pub fn leave(&self, room: &str) -> Result<()> {
+        self.send(json!({"event": "disconnect", "room": room}))
+    }
+
+    //Asks a room for the events it recorded after this sequence number, they arrive in a "roommate:history" event
+    pub fn history(&self, room: &str, since: u64) -> Result<()> {
+        self.send(json!({"event": HISTORY_EVENT, "room": room, "since": since}))
+    }
+
+    pub fn emit(&self, event: &str, payload: impl Serialize) -> Result<()> {
+        let data = serde_json::to_value(payload)?;
+        self.send(json!({"event": event, "data": data}))
+    }
+
+    //Calls the handler with the data of every event with this name, "error" events get the whole message
+    pub fn on<T: DeserializeOwned>(
+        &self,
+        event: &str,
+        handler: impl Fn(T) + Send + Sync + 'static,
+    ) {
+        let event_name = event.to_string();
+
+        let handler: Handler = Arc::new(move |data| match serde_json::from_value(data) {
+            Ok(data) => handler(data),
+            Err(error) => {
+                warn!(event = %event_name, %error, "Event data does not match the handler")
+            }
+        });
+
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .handlers
+            .entry(event.to_string())
+            .or_default()
+            .push(handler);
+    }
+
+    //Emits an event and waits for the room to answer with the reply event, or with an error.
+    //An object payload gets a "request_id" the room copies into its answer (see Room::reply),
+    //answers without one go to the oldest request waiting for that event
+    pub async fn request<T: DeserializeOwned>(
+        &self,
+        event: &str,
+        payload: impl Serialize,
+        reply_event: &str,
+        timeout: Duration,
+    ) -> Result<T> {
+        let mut payload = serde_json::to_value(payload)?;
+        let (answer, answered) = oneshot::channel();
+
+        let request_id = {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            let request_id = subscriptions.next_request;
+            subscriptions.next_request += 1;
+
+            let waiter = Waiter {
+                reply_event: reply_event.to_string(),
+                answer,
+            };
+
+            subscriptions.waiters.insert(request_id, waiter);
+            request_id
+        };
+
+        if let Some(payload) = payload.as_object_mut() {
+            payload.insert("request_id".to_string(), request_id.into());
+        }
+
+        let result = match self.emit(event, payload) {
+            Ok(()) => time::timeout(timeout, answered).await,
+            Err(error) => Ok(Ok(Err(error))),
+        };
+
+        let data = match result {
+            Ok(Ok(answer)) => answer,
+            Ok(Err(_)) => Err(Error::Disconnected),
+            Err(_) => Err(Error::Timeout),
+        };
+
+        //The request gave up, a late answer has nobody to go to
+        if data.is_err() {
+            self.subscriptions
+                .lock()
+                .unwrap()
+                .waiters
+                .remove(&request_id);
+        }
+
+        Ok(serde_json::from_value(data?)?)
+    }
+
+    pub async fn close(self) -> Result<()> {
+        self.send(json!({"event": "close"}))?;
+        let _ = self.outgoing.send(Message::Close(None));
+
+        let _ = self.task.await;
+        Ok(())
+    }
+
+    fn send(&self, frame: Value) -> Result<()> {
+        self.outgoing
+            .send(Message::text(frame.to_string()))
+            .map_err(|_| Error::Disconnected)
+    }
+
+    async fn run<S>(
+        stream: S,
+        mut outgoing: UnboundedReceiver<Message>,
+        subscriptions: Arc<Mutex<Subscriptions>>,
+    ) where
+        S: Stream<Item = tungstenite::Result<Message>>
+            + Sink<Message, Error = tungstenite::Error>
+            + Unpin,
+    {
+        let (mut sender, mut receiver) = stream.split();
+
+        loop {
+            tokio::select! {
+                message = outgoing.recv() => {
+                    let message = match message {
+                        Some(message) => message,
+                        None => break,
+                    };
+
+                    let closing = matches!(message, Message::Close(_));
+
+                    if sender.send(message).await.is_err() || closing {
+                        break;
+                    }
+                }
+
+                message = receiver.next() => {
+                    let text = match message {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => continue,
+                    };
+
+                    match serde_json::from_str::<Value>(&text) {
+                        Ok(frame) => Self::dispatch(&subscriptions, frame),
+                        Err(error) => debug!(%error, "Server sent a message that is not json"),
+                    }
+                }
+            }
+        }
+
+        //Dropping the waiters lets pending requests know the connection is gone
+        subscriptions.lock().unwrap().waiters.clear();
+    }
+
+    fn dispatch(subscriptions: &Mutex<Subscriptions>, mut frame: Value) {
+        let event = match frame["event"].as_str() {
+            Some(event) => event.to_string(),
+            None => return,
+        };
+
+        let data = match frame.get_mut("data") {
+            Some(data) => data.take(),
+            None => frame,
+        };
+
+        let request_id = data.get("request_id").and_then(Value::as_u64);
+
+        //Handlers run without the lock so they can subscribe to more events
+        let (waiter, handlers) = {
+            let mut subscriptions = subscriptions.lock().unwrap();
+            let waiter = subscriptions.waiter(&event, request_id);
+            let handlers = subscriptions
+                .handlers
+                .get(&event)
+                .cloned()
+                .unwrap_or_default();
+
+            (waiter, handlers)
+        };
+
+        if let Some(waiter) = waiter {
+            let answer = match event.as_str() {
+                "error" => Err(Error::Server(
+                    data["message"].as_str().unwrap_or_default().to_string(),
+                )),
+                _ => Ok(data.clone()),
+            };
+
+            let _ = waiter.answer.send(answer);
+        }
+
+        for handler in handlers {
+            handler(data.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    //Answers "double" with twice its value, a value of 1 is answered last
+    async fn server() -> Client {
+        let (room, actor) = RoomBuilder::new()
+            .namespace("calc")
+            .on("double", |room, payload, emiter| {
+                Box::pin(async move {
+                    let value = payload["value"].as_u64().unwrap_or_default();
+
+                    if value == 1 {
+                        time::sleep(Duration::from_millis(200)).await;
+                    }
+
+                    let answer = json!({ "value": value * 2 });
+                    let _ = room.reply(emiter, &payload, "doubled", answer).await;
+                })
+            })
+            .build();
+
+        room.run(actor);
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let mut rooms = HashMap::new();
+        rooms.insert(room.namespace.clone(), room.sender.clone());
+        SocketListener::new(("127.0.0.1", port), rooms)
+            .listen()
+            .await
+            .unwrap();
+
+        let client = Client::connect(&format!("ws://127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        client.join("calc").unwrap();
+        client
+    }
+
+    async fn double(client: &Client, value: u64) -> Result<Value> {
+        let timeout = Duration::from_secs(2);
+        client
+            .request("double", json!({ "value": value }), "doubled", timeout)
+            .await
+    }
+
+    #[tokio::test]
+    async fn replies_go_to_the_request_with_their_id() {
+        let client = server().await;
+
+        //The first request is answered after the second one
+        let (first, second) = tokio::join!(double(&client, 1), double(&client, 2));
+
+        assert_eq!(first.unwrap()["value"], 2);
+        assert_eq!(second.unwrap()["value"], 4);
+        assert!(client.subscriptions.lock().unwrap().waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn an_error_reply_fails_the_request() {
+        let client = server().await;
+        let event = "x".repeat(Limits::default().max_event_name_len + 1);
+        let timeout = Duration::from_secs(2);
+
+        let result: Result<Value> = client.request(&event, json!({}), "done", timeout).await;
+
+        assert!(matches!(result, Err(Error::Server(_))));
+        assert!(client.subscriptions.lock().unwrap().waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_timed_out_request_stops_waiting() {
+        let client = server().await;
+        let timeout = Duration::from_millis(100);
+
+        let result: Result<Value> = client
+            .request("double", json!({ "value": 1 }), "doubled", timeout)
+            .await;
+
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(client.subscriptions.lock().unwrap().waiters.is_empty());
+
+        //The late answer has nobody to go to, the next request gets its own
+        assert_eq!(double(&client, 3).await.unwrap()["value"], 6);
+    }
+}
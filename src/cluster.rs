@@ -0,0 +1,365 @@
+use crate::{error::Result, lines::Lines};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::{JoinHandle, JoinSet},
+    time,
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+//Time a node has to introduce itself to the broker before being dropped
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+//What a room on one node tells the rooms with the same namespace on the other nodes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Relay {
+    //An event for every user of the room, but the one that sent it
+    Event {
+        room: String,
+        event: String,
+        payload: Value,
+        except: Option<Uuid>,
+    },
+
+    //An event for a single user connected to another node
+    Whisper {
+        room: String,
+        user: Uuid,
+        event: String,
+        payload: Value,
+    },
+
+    Join {
+        room: String,
+        user: Uuid,
+        node: Uuid,
+    },
+
+    Leave {
+        room: String,
+        user: Uuid,
+    },
+
+    //A node went away, with every user it had
+    NodeLeft {
+        node: Uuid,
+    },
+
+    //A node connected, every room answers with a join for each of its users so the node knows them
+    NodeJoined {
+        node: Uuid,
+    },
+}
+
+impl Relay {
+    //The room this is for, None when it is for every room
+    pub fn room(&self) -> Option<&str> {
+        match self {
+            Relay::Event { room, .. }
+            | Relay::Whisper { room, .. }
+            | Relay::Join { room, .. }
+            | Relay::Leave { room, .. } => Some(room),
+
+            Relay::NodeLeft { .. } | Relay::NodeJoined { .. } => None,
+        }
+    }
+}
+
+//Carries relays between the processes that run the same rooms
+pub trait Adapter: Send + Sync {
+    //Identifies this process among the others
+    fn node(&self) -> Uuid;
+
+    //Sends the relay to the other nodes, without waiting for them
+    fn publish(&self, relay: Relay);
+
+    //The relays other nodes publish for this room
+    fn subscribe(&self, room: &str) -> UnboundedReceiver<Relay>;
+}
+
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    node: Uuid,
+}
+
+//A broker that passes every line a node sends to all the other nodes
+pub struct TcpBroker {
+    pub addr: SocketAddr,
+    handle: JoinHandle<()>,
+}
+
+type Nodes = Arc<Mutex<HashMap<Uuid, UnboundedSender<String>>>>;
+
+impl TcpBroker {
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let nodes: Nodes = Arc::new(Mutex::new(HashMap::new()));
+
+        let handle = tokio::spawn(async move {
+            //Owned by the accept loop, so stopping the broker stops serving the nodes too
+            let mut serving = JoinSet::new();
+
+            loop {
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+
+                    //The nodes that left are reaped so the set only holds the connected ones
+                    Some(_) = serving.join_next() => continue,
+                };
+
+                let (stream, peer) = match accepted {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        warn!(%error, "Broker failed to accept a node");
+                        time::sleep(MIN_RECONNECT_BACKOFF).await;
+                        continue;
+                    }
+                };
+
+                debug!(%peer, "Node connected to the broker");
+                serving.spawn(Self::serve_node(stream, nodes.clone()));
+            }
+        });
+
+        info!(%addr, "Broker listening");
+        Ok(Self { addr, handle })
+    }
+
+    pub fn stop(self) {
+        self.handle.abort();
+    }
+
+    async fn serve_node(stream: TcpStream, nodes: Nodes) {
+        let (mut lines, mut writer) = Lines::new(Box::new(stream));
+
+        let node = match time::timeout(HELLO_TIMEOUT, lines.next_line()).await {
+            Ok(Ok(Some(line))) => match serde_json::from_str::<Hello>(&line) {
+                Ok(hello) => hello.node,
+                Err(error) => {
+                    warn!(%error, "Node did not introduce itself");
+                    return;
+                }
+            },
+
+            Err(_) => {
+                warn!("Node did not introduce itself in time");
+                return;
+            }
+
+            _ => return,
+        };
+
+        let (sender, mut receiver) = unbounded_channel::<String>();
+        nodes.lock().unwrap().insert(node, sender);
+        info!(%node, "Node joined the broker");
+
+        let node_joined = serde_json::to_string(&Relay::NodeJoined { node }).unwrap();
+        Self::forward(&nodes, node, format!("{}\n", node_joined));
+
+        let writing = async move {
+            while let Some(line) = receiver.recv().await {
+                if writer.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        };
+
+        let reading = async {
+            loop {
+                match lines.next_line().await {
+                    Ok(Some(line)) => Self::forward(&nodes, node, line),
+                    Ok(None) => break,
+                    Err(error) => {
+                        warn!(%node, %error, "Dropped a node that sent a bad line");
+                        break;
+                    }
+                }
+            }
+        };
+
+        //Both end with the task, whichever of them stops first
+        tokio::select! {
+            _ = writing => {}
+            _ = reading => {}
+        }
+
+        nodes.lock().unwrap().remove(&node);
+        info!(%node, "Node left the broker");
+
+        let node_left = serde_json::to_string(&Relay::NodeLeft { node }).unwrap();
+        Self::forward(&nodes, node, format!("{}\n", node_left));
+    }
+
+    fn forward(nodes: &Nodes, from: Uuid, line: String) {
+        for (node, sender) in nodes.lock().unwrap().iter() {
+            if *node != from {
+                let _ = sender.send(line.clone());
+            }
+        }
+    }
+}
+
+type Subscribers = Arc<Mutex<HashMap<String, Vec<UnboundedSender<Relay>>>>>;
+
+//Adapter that talks to a TcpBroker, reconnecting whenever the connection drops.
+//Relays published while it is disconnected are lost
+pub struct TcpAdapter {
+    node: Uuid,
+    outgoing: UnboundedSender<Relay>,
+    subscribers: Subscribers,
+}
+
+impl TcpAdapter {
+    pub fn connect(addr: SocketAddr) -> Arc<Self> {
+        let node = Uuid::new_v4();
+        let (outgoing, outgoing_receiver) = unbounded_channel::<Relay>();
+        let subscribers: Subscribers = Arc::new(Mutex::new(HashMap::new()));
+
+        tokio::spawn(Self::run(
+            addr,
+            node,
+            outgoing_receiver,
+            subscribers.clone(),
+        ));
+
+        Arc::new(Self {
+            node,
+            outgoing,
+            subscribers,
+        })
+    }
+
+    async fn run(
+        addr: SocketAddr,
+        node: Uuid,
+        mut outgoing: UnboundedReceiver<Relay>,
+        subscribers: Subscribers,
+    ) {
+        let mut backoff = MIN_RECONNECT_BACKOFF;
+
+        loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => {
+                    backoff = MIN_RECONNECT_BACKOFF;
+                    info!(%addr, %node, "Connected to the broker");
+
+                    //Only returns false once the adapter was dropped
+                    if !Self::relay(stream, node, &mut outgoing, &subscribers).await {
+                        return;
+                    }
+
+                    warn!(%addr, "Lost the connection to the broker");
+                }
+
+                Err(error) => warn!(%addr, %error, ?backoff, "Failed to connect to the broker"),
+            }
+
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+
+            //Nothing can be sent meanwhile, so drop what piled up
+            while outgoing.try_recv().is_ok() {}
+        }
+    }
+
+    async fn relay(
+        stream: TcpStream,
+        node: Uuid,
+        outgoing: &mut UnboundedReceiver<Relay>,
+        subscribers: &Subscribers,
+    ) -> bool {
+        let (mut lines, mut writer) = Lines::new(Box::new(stream));
+
+        let hello = format!("{}\n", serde_json::to_string(&Hello { node }).unwrap());
+        if writer.write_all(hello.as_bytes()).await.is_err() {
+            return true;
+        }
+
+        //The other nodes forgot the users of this one while it was away, so the rooms announce them again
+        Self::dispatch(subscribers, Relay::NodeJoined { node });
+
+        loop {
+            tokio::select! {
+                relay = outgoing.recv() => {
+                    let relay = match relay {
+                        Some(relay) => relay,
+                        None => return false,
+                    };
+
+                    let line = format!("{}\n", serde_json::to_string(&relay).unwrap());
+                    if writer.write_all(line.as_bytes()).await.is_err() {
+                        return true;
+                    }
+                }
+
+                line = lines.next_line() => {
+                    let line = match line {
+                        Ok(Some(line)) => line,
+                        _ => return true,
+                    };
+
+                    match serde_json::from_str::<Relay>(&line) {
+                        Ok(relay) => Self::dispatch(subscribers, relay),
+                        Err(error) => debug!(%error, "Broker sent a line that is not a relay"),
+                    }
+                }
+            }
+        }
+    }
+
+    fn dispatch(subscribers: &Subscribers, relay: Relay) {
+        let mut subscribers = subscribers.lock().unwrap();
+
+        match relay.room() {
+            Some(room) => {
+                if let Some(senders) = subscribers.get_mut(room) {
+                    senders.retain(|sender| sender.send(relay.clone()).is_ok());
+                }
+            }
+
+            None => {
+                for senders in subscribers.values_mut() {
+                    senders.retain(|sender| sender.send(relay.clone()).is_ok());
+                }
+            }
+        }
+    }
+}
+
+impl Adapter for TcpAdapter {
+    fn node(&self) -> Uuid {
+        self.node
+    }
+
+    fn publish(&self, relay: Relay) {
+        let _ = self.outgoing.send(relay);
+    }
+
+    fn subscribe(&self, room: &str) -> UnboundedReceiver<Relay> {
+        let (sender, receiver) = unbounded_channel::<Relay>();
+
+        self.subscribers
+            .lock()
+            .unwrap()
+            .entry(room.to_string())
+            .or_default()
+            .push(sender);
+
+        receiver
+    }
+}
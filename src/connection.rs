@@ -1,49 +1,482 @@
-use crate::{protocol::Room, user::User};
+#[cfg(feature = "tls")]
+use crate::tls::{Tls, TlsConfig, Watch};
+use crate::{
+    error::Result,
+    health::{respond, Health, HealthPaths, Rewind},
+    limits::{ConnectionGuard, ConnectionLimits, ConnectionTracker, Limits, Rejection},
+    metrics::metrics,
+    protocol::Room,
+    session::SessionStore,
+    user::User,
+};
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, future::Future, io, net::IpAddr, sync::Arc, time::Duration};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::{
-    net::{TcpListener, ToSocketAddrs},
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
     sync::mpsc::UnboundedSender,
     task::JoinHandle,
+    time,
 };
-use tokio_tungstenite::accept_async;
+use tokio_tungstenite::accept_hdr_async_with_config;
+use tracing::{info_span, warn, Instrument};
+use tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+};
+#[cfg(unix)]
+use uuid::Uuid;
+
+//Tells who is behind a connection from its handshake request, for the per identity limit
+pub type Identify = dyn Fn(&Request) -> Option<String> + Send + Sync;
 
-pub struct SocketListener<A: ToSocketAddrs + Send + Sync> {
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+pub struct SocketListener<A> {
     pub addr: A,
     pub room_channels: Arc<HashMap<String, UnboundedSender<Room>>>,
+    pub limits: Limits,
+    pub health: Arc<Health>,
+    pub health_paths: Option<HealthPaths>,
+    pub handshake_timeout: Duration,
+    pub connections: Arc<ConnectionTracker>,
+    pub identify: Option<Arc<Identify>>,
+    pub sessions: Option<Arc<SessionStore>>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+    #[cfg(feature = "tls")]
+    tls_acceptor: Option<Arc<Tls>>,
+    #[cfg(feature = "tls")]
+    tls_watch: Option<Watch>,
 }
 
-impl<A: ToSocketAddrs + Send + Sync + 'static> SocketListener<A> {
+impl<A: Send + Sync + 'static> SocketListener<A> {
     pub fn new(addr: A, room_channels: HashMap<String, UnboundedSender<Room>>) -> Self {
         let room_channels = Arc::new(room_channels);
+        let health = Arc::new(Health::new(room_channels.clone()));
 
         Self {
             addr,
             room_channels,
+            limits: Limits::default(),
+            health,
+            health_paths: None,
+            handshake_timeout: Duration::from_secs(10),
+            connections: Arc::new(ConnectionTracker::default()),
+            identify: None,
+            sessions: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            tls_acceptor: None,
+            #[cfg(feature = "tls")]
+            tls_watch: None,
+        }
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    //Time a client has to finish the tls and websocket handshakes before being dropped
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.connections = Arc::new(ConnectionTracker::new(limits));
+        self
+    }
+
+    pub fn identify(
+        mut self,
+        identify: impl Fn(&Request) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.identify = Some(Arc::new(identify));
+        self
+    }
+
+    //Answers plain http requests on these paths instead of dropping them
+    pub fn health_paths(mut self, paths: HealthPaths) -> Self {
+        self.health_paths = Some(paths);
+        self
+    }
+
+    //Keeps the session of a dropped connection for this long so the client can resume it
+    pub fn session_resumption(mut self, grace: Duration) -> Self {
+        self.sessions = Some(Arc::new(SessionStore::new(grace)));
+        self
+    }
+
+    //Same as session_resumption with a store set up by hand, like one with an offline queue
+    pub fn session_store(mut self, sessions: SessionStore) -> Self {
+        self.sessions = Some(Arc::new(sessions));
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    #[cfg_attr(not(feature = "tls"), allow(unused_mut))]
+    fn load_tls(mut self) -> Result<Self> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = self.tls.clone() {
+            let tls = Tls::new(config)?;
+            self.tls_watch = Some(tls.watch());
+            self.tls_acceptor = Some(tls);
         }
+
+        Ok(self)
     }
 
-    pub fn listen(self) -> JoinHandle<()> {
+    #[cfg_attr(not(feature = "tls"), allow(unused_mut))]
+    fn serve<L: Accept>(mut self, connection_listener: L) -> JoinHandle<()> {
+        //Owned by the accept loop, so aborting it stops the certificate reload too
+        #[cfg(feature = "tls")]
+        let tls_watch = self.tls_watch.take();
+
+        let listener = Arc::new(self);
+
         tokio::task::spawn(async move {
-            let connection_listener = TcpListener::bind(&self.addr)
-                .await
-                .expect("The address of the socket is not valid");
+            #[cfg(feature = "tls")]
+            let _tls_watch = tls_watch;
 
-            let mut user_task_handlers: Vec<JoinHandle<()>> = vec![];
+            let mut backoff = MIN_ACCEPT_BACKOFF;
 
             loop {
-                let (stream, _) = connection_listener.accept().await.unwrap();
-                let result = accept_async(stream).await;
+                let (stream, peer) = match connection_listener.accept().await {
+                    Ok(connection) => {
+                        backoff = MIN_ACCEPT_BACKOFF;
+                        connection
+                    }
 
-                match result {
-                    Ok(ws) => {
-                        let user = User::new(ws, Arc::downgrade(&self.room_channels));
-                        user_task_handlers.push(user.run());
+                    //Running out of file descriptors or memory is usually transient, so wait a bit and retry
+                    Err(error) => {
+                        warn!(%error, ?backoff, "Failed to accept a connection");
+                        time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                        continue;
                     }
+                };
+
+                let listener = listener.clone();
+                let span = info_span!("connection", peer = %peer.name);
+
+                //Each handshake runs on its own task so a slow client can't hold back the others
+                tokio::spawn(
+                    async move {
+                        let guard = listener.connections.acquire(peer.ip);
+                        let handshake = listener.accept(stream, guard);
+
+                        match time::timeout(listener.handshake_timeout, handshake).await {
+                            //The guard is held for as long as the user is connected
+                            Ok(Some((user, _guard))) => {
+                                let _ = user.await;
+                            }
 
-                    Err(_) => {}
+                            Ok(None) => {}
+
+                            Err(_) => {
+                                warn!("Handshake timed out");
+                                metrics().connections_rejected.inc();
+                            }
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+        })
+    }
+
+    async fn accept<S>(
+        &self,
+        stream: S,
+        guard: Result<ConnectionGuard, Rejection>,
+    ) -> Option<(JoinHandle<()>, ConnectionGuard)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls_acceptor {
+            return match tls.accept(stream).await {
+                Ok(stream) => self.handshake(stream, guard).await,
+
+                Err(error) => {
+                    warn!(%error, "Tls handshake failed");
+                    metrics().connections_rejected.inc();
+                    None
                 }
+            };
+        }
+
+        self.handshake(stream, guard).await
+    }
+
+    async fn handshake<S>(
+        &self,
+        stream: S,
+        guard: Result<ConnectionGuard, Rejection>,
+    ) -> Option<(JoinHandle<()>, ConnectionGuard)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut stream = Rewind::new(stream);
+
+        //Health checks are answered even when the listener is full
+        if let Some(paths) = &self.health_paths {
+            if self.health.intercept(&mut stream, paths).await {
+                return None;
+            }
+        }
+
+        let guard = match self.health.is_draining() {
+            true => Err(Rejection::Draining),
+            false => guard,
+        };
+
+        let mut guard = match guard {
+            Ok(guard) => guard,
+
+            Err(rejection) => {
+                warn!(reason = rejection.reason(), "Connection rejected");
+                metrics().connections_rejected.inc();
+
+                let (code, status) = rejection.status();
+                respond(
+                    &mut stream,
+                    &format!("{} {}", code, status),
+                    rejection.reason(),
+                )
+                .await;
+                return None;
+            }
+        };
+
+        let identify = self.identify.clone();
+        let mut rejected = None;
+
+        //tungstenite decides the shape of the error response
+        #[allow(clippy::result_large_err)]
+        let callback = |request: &Request, response: Response| {
+            let identity = match identify {
+                Some(identify) => identify(request),
+                None => None,
+            };
+
+            if let Some(identity) = identity {
+                if let Err(rejection) = guard.identify(identity) {
+                    let (code, _) = rejection.status();
+                    let mut error = ErrorResponse::new(Some(rejection.reason().to_string()));
+                    *error.status_mut() = StatusCode::from_u16(code).unwrap();
+
+                    rejected = Some(rejection);
+                    return Err(error);
+                }
+            }
+
+            Ok(response)
+        };
+
+        let config = self.limits.websocket_config();
+        let ws = match accept_hdr_async_with_config(stream, callback, Some(config)).await {
+            Ok(ws) => ws,
+
+            Err(error) => {
+                match rejected {
+                    Some(rejection) => warn!(reason = rejection.reason(), "Connection rejected"),
+                    None => warn!(%error, "Websocket handshake failed"),
+                }
+
+                metrics().connections_rejected.inc();
+                return None;
+            }
+        };
+
+        metrics().connections_accepted.inc();
+
+        let mut user = User::new(ws, Arc::downgrade(&self.room_channels), self.limits.clone());
+
+        if let Some(sessions) = &self.sessions {
+            user = user.sessions(sessions.clone());
+        }
+
+        Some((user.run(), guard))
+    }
+}
+
+impl<A: ToSocketAddrs + Send + Sync + 'static> SocketListener<A> {
+    //Binds the socket and spawns the accept loop, failing if the address or the certificate is not valid
+    pub async fn listen(self) -> Result<JoinHandle<()>> {
+        let connection_listener = TcpListener::bind(&self.addr).await?;
+
+        Ok(self.load_tls()?.serve(connection_listener))
+    }
+}
+
+#[cfg(unix)]
+impl SocketListener<UnixAddr> {
+    //Binds the unix socket, replacing a stale one left by a previous run, and spawns the accept loop.
+    //The socket file is removed when the loop stops
+    pub async fn listen(self) -> Result<JoinHandle<()>> {
+        let connection_listener = self.addr.bind()?;
+
+        Ok(self.load_tls()?.serve(connection_listener))
+    }
+}
+
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct UnixAddr {
+    pub path: PathBuf,
+
+    //Permission bits given to the socket file, like 0o660
+    pub mode: Option<u32>,
+}
+
+#[cfg(unix)]
+impl UnixAddr {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            mode: None,
+        }
+    }
+
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    fn bind(&self) -> io::Result<UnixSocket> {
+        if let Ok(metadata) = fs::symlink_metadata(&self.path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "The path of the socket exists and it is not a socket",
+                ));
+            }
+
+            //Nobody answering means the socket was left behind by a process that is gone
+            match std::os::unix::net::UnixStream::connect(&self.path) {
+                Ok(_) => return Err(io::Error::from(io::ErrorKind::AddrInUse)),
+                Err(_) => fs::remove_file(&self.path)?,
             }
+        }
+
+        let listener = match self.mode {
+            Some(mode) => self.bind_private(mode)?,
+            None => UnixListener::bind(&self.path)?,
+        };
+
+        let inode = fs::symlink_metadata(&self.path)?.ino();
+
+        Ok(UnixSocket {
+            listener,
+            path: self.path.clone(),
+            inode,
         })
     }
+
+    //Binds in a directory only this process can enter and moves the socket into place once it has
+    //its mode, so nobody can connect while it still has the permissions of the umask
+    fn bind_private(&self, mode: u32) -> io::Result<UnixListener> {
+        let parent = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        let private = parent.join(format!(".roommate-{}", Uuid::new_v4()));
+        fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+        let socket = private.join("socket");
+
+        let result = UnixListener::bind(&socket).and_then(|listener| {
+            fs::set_permissions(&socket, fs::Permissions::from_mode(mode))?;
+            fs::rename(&socket, &self.path)?;
+            Ok(listener)
+        });
+
+        let _ = fs::remove_file(&socket);
+        let _ = fs::remove_dir(&private);
+        result
+    }
+}
+
+//A bound unix socket, its file is removed when the accept loop stops
+#[cfg(unix)]
+struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    inode: u64,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        //Another listener may have replaced the file since, that one is left alone
+        if let Ok(metadata) = fs::symlink_metadata(&self.path) {
+            if metadata.ino() == self.inode {
+                let _ = fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+struct Peer {
+    name: String,
+
+    //Unix sockets have no ip, so the per ip limit does not apply to them
+    ip: Option<IpAddr>,
+}
+
+//The part of a listener socket the accept loop needs
+trait Accept: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn accept(&self) -> impl Future<Output = io::Result<(Self::Stream, Peer)>> + Send;
+}
+
+impl Accept for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> io::Result<(TcpStream, Peer)> {
+        let (stream, addr) = TcpListener::accept(self).await?;
+
+        let peer = Peer {
+            name: addr.to_string(),
+            ip: Some(addr.ip()),
+        };
+
+        Ok((stream, peer))
+    }
+}
+
+#[cfg(unix)]
+impl Accept for UnixSocket {
+    type Stream = UnixStream;
+
+    async fn accept(&self) -> io::Result<(UnixStream, Peer)> {
+        let (stream, addr) = self.listener.accept().await?;
+
+        let peer = Peer {
+            name: format!("{:?}", addr),
+            ip: None,
+        };
+
+        Ok((stream, peer))
+    }
 }
@@ -24,7 +24,7 @@ impl<T: 'static> Data<T> {
 impl<T> Clone for Data<T> {
     fn clone(&self) -> Self {
         Self {
-            data_type_id: self.data_type_id.clone(),
+            data_type_id: self.data_type_id,
             inner_data: self.inner_data.clone(),
         }
     }
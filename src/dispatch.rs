@@ -0,0 +1,54 @@
+use crate::{event::BoxFut, protocol::Emiter};
+use std::collections::HashMap;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+//How a room runs the handlers of the events it receives
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DispatchMode {
+    //One handler at a time, the next event waits until the handler before it finished.
+    //A handler that waits for the handler of another event of this room will never finish,
+    //emiting, whispering, asking for the members and users joining or leaving still work
+    Sequential,
+
+    //Handlers of events from the same user or room run in order, different emiters run at the same time
+    PerSender,
+
+    //Every handler runs as soon as its event arrives
+    #[default]
+    Concurrent,
+}
+
+//A queue of handlers for every emiter, each one drained by its own task
+#[derive(Default)]
+pub(crate) struct SenderQueues {
+    queues: HashMap<Emiter, UnboundedSender<BoxFut>>,
+}
+
+impl SenderQueues {
+    pub(crate) fn push(&mut self, emiter: Emiter, handler: BoxFut) {
+        let handler = match self.queues.get(&emiter) {
+            Some(queue) => match queue.send(handler) {
+                Ok(()) => return,
+                Err(error) => error.0,
+            },
+
+            None => handler,
+        };
+
+        let (queue, mut handlers) = unbounded_channel::<BoxFut>();
+        let _ = queue.send(handler);
+        self.queues.insert(emiter, queue);
+
+        //Each handler gets its own task so a panic only loses that one
+        tokio::spawn(async move {
+            while let Some(handler) = handlers.recv().await {
+                let _ = tokio::spawn(handler).await;
+            }
+        });
+    }
+
+    //The handlers already queued still run, then the task of the emiter ends
+    pub(crate) fn remove(&mut self, emiter: &Emiter) {
+        self.queues.remove(emiter);
+    }
+}
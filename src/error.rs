@@ -0,0 +1,136 @@
+use crate::protocol;
+use serde_json::{json, Value};
+use std::{fmt, io};
+use uuid::Uuid;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    //The socket could not be bound or a certificate could not be read
+    Io(io::Error),
+
+    //A client sent something that does not follow the protocol
+    Protocol(protocol::Error),
+
+    RoomNotFound(String),
+
+    UserNotFound(Uuid),
+
+    //The user asked something of a room it did not join
+    NotInRoom(String),
+
+    //The room exists but its run loop already finished
+    RoomClosed(String),
+
+    //Every room of the server was dropped
+    ServerStopped,
+
+    //The session token is unknown or its grace window is over
+    SessionNotFound,
+
+    //The websocket connection of a client failed
+    WebSocket(Box<tungstenite::Error>),
+
+    //A payload could not be turned into or out of json
+    Json(serde_json::Error),
+
+    //The sqlite database failed to run a query
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+
+    //An answer did not arrive in time
+    Timeout,
+
+    //The connection is already closed
+    Disconnected,
+
+    //The server answered a request with an error event
+    Server(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{}", error),
+
+            Error::Protocol(error) => write!(f, "{}", error),
+
+            Error::RoomNotFound(room) => write!(f, "The room {} does not exist", room),
+
+            Error::UserNotFound(user) => write!(f, "The user {} is not in this room", user),
+
+            Error::NotInRoom(room) => write!(f, "You are not in the room {}", room),
+
+            Error::RoomClosed(room) => write!(f, "The room {} is no longer running", room),
+
+            Error::ServerStopped => write!(f, "The server is no longer running"),
+
+            Error::SessionNotFound => write!(f, "The session does not exist or it expired"),
+
+            Error::WebSocket(error) => write!(f, "{}", error),
+
+            Error::Json(error) => write!(f, "{}", error),
+
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite(error) => write!(f, "{}", error),
+
+            Error::Timeout => write!(f, "The answer did not arrive in time"),
+
+            Error::Disconnected => write!(f, "The connection is closed"),
+
+            Error::Server(message) => write!(f, "The server answered with an error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Protocol(error) => Some(error),
+            Error::WebSocket(error) => Some(error.as_ref()),
+            Error::Json(error) => Some(error),
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<protocol::Error> for Error {
+    fn from(error: protocol::Error) -> Self {
+        Error::Protocol(error)
+    }
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(error: tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(error))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Json(error)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(error: rusqlite::Error) -> Self {
+        Error::Sqlite(error)
+    }
+}
+
+impl From<Error> for Value {
+    fn from(error: Error) -> Self {
+        json!({"event" : "error", "message": error.to_string()})
+    }
+}
@@ -23,6 +23,12 @@ impl EventMap {
     }
 }
 
+impl Default for EventMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Deref for EventMap {
     type Target = HashMap<String, Event>;
 
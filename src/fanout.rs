@@ -0,0 +1,200 @@
+use crate::{
+    metrics::metrics,
+    protocol::{self, Frame},
+};
+use futures_util::{stream::unfold, Stream, StreamExt};
+use serde_json::json;
+use std::{fmt, future::poll_fn, pin::Pin, sync::Arc, task::Poll};
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+//How a room gets the events it emits to its users
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FanOut {
+    //The room pushes every event into the channel of each user
+    #[default]
+    PerUser,
+
+    //The room pushes every event once into a ring its users read from, so emiting costs the same
+    //for any number of users. Whispers and history still go through the channel of the user,
+    //so they may arrive before or after the events of the ring sent around the same time
+    Ring {
+        capacity: usize,
+        lag: LagPolicy,
+    },
+}
+
+//What happens to a user that fell so far behind that the ring dropped events it had not read
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LagPolicy {
+    //The user is told how many events it missed and keeps reading from the oldest one left
+    #[default]
+    Skip,
+
+    //The user is told how many events it missed and leaves the room
+    Disconnect,
+}
+
+//An event in the ring, for every member but the one that sent it
+#[derive(Clone)]
+pub(crate) struct Ringed {
+    pub(crate) frame: Arc<Frame>,
+    pub(crate) except: Option<Uuid>,
+}
+
+//The end of the ring of a room that a member reads, the room sends it when the member joins
+pub struct Subscription {
+    pub room: String,
+    pub member: Uuid,
+    pub lag: LagPolicy,
+    receiver: broadcast::Receiver<Ringed>,
+}
+
+impl Subscription {
+    pub(crate) fn new(
+        room: String,
+        member: Uuid,
+        lag: LagPolicy,
+        receiver: broadcast::Receiver<Ringed>,
+    ) -> Self {
+        Self {
+            room,
+            member,
+            lag,
+            receiver,
+        }
+    }
+}
+
+//A copy only reads the events sent after it was made
+impl Clone for Subscription {
+    fn clone(&self) -> Self {
+        Self {
+            room: self.room.clone(),
+            member: self.member,
+            lag: self.lag,
+            receiver: self.receiver.resubscribe(),
+        }
+    }
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscription")
+            .field("room", &self.room)
+            .field("member", &self.member)
+            .field("lag", &self.lag)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Subscription {
+    fn eq(&self, other: &Self) -> bool {
+        self.room == other.room
+            && self.member == other.member
+            && self.lag == other.lag
+            && self.receiver.same_channel(&other.receiver)
+    }
+}
+
+//What the ring of a room gave a member
+pub(crate) enum Delivery {
+    Frame(Arc<Frame>),
+
+    //How many events the member missed
+    Lagged(u64),
+}
+
+//Sync too, so an event stream body can hold it
+type Deliveries = Pin<Box<dyn Stream<Item = Delivery> + Send + Sync>>;
+
+//The rings a member reads, one for every ring room it is in
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    rings: Vec<(String, LagPolicy, Deliveries)>,
+
+    //The ring polled first next time, so a busy ring can't keep the others waiting
+    first: usize,
+}
+
+impl Subscriptions {
+    pub(crate) fn insert(&mut self, subscription: Subscription) {
+        let Subscription {
+            room,
+            member,
+            lag,
+            receiver,
+        } = subscription;
+
+        let deliveries = unfold(receiver, move |mut receiver| async move {
+            loop {
+                let delivery = match receiver.recv().await {
+                    Ok(ringed) if ringed.except == Some(member) => continue,
+                    Ok(ringed) => Delivery::Frame(ringed.frame),
+                    Err(RecvError::Lagged(missed)) => Delivery::Lagged(missed),
+                    Err(RecvError::Closed) => return None,
+                };
+
+                return Some((delivery, receiver));
+            }
+        });
+
+        self.remove(&room);
+        self.rings.push((room, lag, Box::pin(deliveries)));
+    }
+
+    pub(crate) fn remove(&mut self, room: &str) {
+        self.rings.retain(|(ring_room, ..)| ring_room != room);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.rings.clear();
+    }
+
+    //The next delivery of any ring with its room, it never finishes while there are no rings
+    pub(crate) async fn next(&mut self) -> (String, LagPolicy, Delivery) {
+        poll_fn(|cx| {
+            let count = self.rings.len();
+
+            for offset in 0..count {
+                let index = (self.first + offset) % count;
+                let (room, lag, deliveries) = &mut self.rings[index];
+
+                match deliveries.poll_next_unpin(cx) {
+                    Poll::Ready(Some(delivery)) => {
+                        self.first = index + 1;
+                        return Poll::Ready((room.clone(), *lag, delivery));
+                    }
+
+                    //The room stopped, the other rings are polled again right away
+                    Poll::Ready(None) => {
+                        drop(self.rings.remove(index));
+                        cx.waker().wake_by_ref();
+                        break;
+                    }
+
+                    Poll::Pending => {}
+                }
+            }
+
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+//What a member is told when it missed events of a ring
+pub(crate) fn lagged(room: &str, missed: u64, lag: LagPolicy) -> protocol::User {
+    metrics()
+        .events_lagged
+        .with_label_values(&[room])
+        .inc_by(missed);
+
+    let data = json!({
+        "room": room,
+        "missed": missed,
+        "left": lag == LagPolicy::Disconnect,
+    });
+
+    protocol::User::Event(protocol::LAGGED_EVENT.to_string(), data)
+}
@@ -0,0 +1,399 @@
+#[cfg(feature = "tls")]
+use crate::tls::{Tls, TlsClientConfig, TlsConfig};
+use crate::{
+    error::Result,
+    lines::{Lines, Stream, Writer},
+    protocol,
+    user::Rooms,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+    time,
+};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+//First line of a link, sent by the server that opens it
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    token: String,
+    from: String,
+    to: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Welcome {
+    ok: bool,
+    error: Option<String>,
+}
+
+//An event between the two linked rooms, the emiter is always the room at the other end
+#[derive(Serialize, Deserialize)]
+struct Frame {
+    event: String,
+    data: Value,
+}
+
+//Accepts links from rooms on other servers that know the token
+pub struct FederationListener<A> {
+    pub addr: A,
+    pub token: String,
+    pub rooms: Rooms,
+    pub handshake_timeout: Duration,
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+}
+
+impl<A: ToSocketAddrs> FederationListener<A> {
+    pub fn new(
+        addr: A,
+        token: &str,
+        rooms: HashMap<String, UnboundedSender<protocol::Room>>,
+    ) -> Self {
+        Self {
+            addr,
+            token: String::from(token),
+            rooms: Arc::new(rooms),
+            handshake_timeout: Duration::from_secs(10),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    //Time a server has to finish the tls handshake and say hello before being dropped
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    //Links are accepted over tls with this certificate, which is reloaded like the one of a SocketListener
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: TlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    pub async fn listen(self) -> Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(&self.addr).await?;
+        let token = Arc::new(self.token);
+        let rooms = self.rooms;
+        let handshake_timeout = self.handshake_timeout;
+
+        #[cfg(feature = "tls")]
+        let tls = match self.tls {
+            Some(config) => Some(Tls::new(config)?),
+            None => None,
+        };
+
+        //Owned by the accept loop, so aborting it stops the certificate reload too
+        #[cfg(feature = "tls")]
+        let tls_watch = tls.as_ref().map(Tls::watch);
+
+        Ok(tokio::spawn(async move {
+            #[cfg(feature = "tls")]
+            let _tls_watch = tls_watch;
+
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        warn!(%error, "Failed to accept a room link");
+                        time::sleep(MIN_RECONNECT_BACKOFF).await;
+                        continue;
+                    }
+                };
+
+                let span = info_span!("link", %peer);
+                let token = token.clone();
+                let rooms = rooms.clone();
+                #[cfg(feature = "tls")]
+                let tls = tls.clone();
+
+                tokio::spawn(
+                    async move {
+                        #[cfg(feature = "tls")]
+                        let stream: Box<dyn Stream> = match tls {
+                            Some(tls) => {
+                                match time::timeout(handshake_timeout, tls.accept(stream)).await {
+                                    Ok(Ok(stream)) => Box::new(stream),
+                                    Ok(Err(error)) => return warn!(%error, "Tls handshake failed"),
+                                    Err(_) => return warn!("Tls handshake timed out"),
+                                }
+                            }
+
+                            None => Box::new(stream),
+                        };
+
+                        #[cfg(not(feature = "tls"))]
+                        let stream: Box<dyn Stream> = Box::new(stream);
+
+                        accept_link(stream, token, rooms, handshake_timeout).await
+                    }
+                    .instrument(span),
+                );
+            }
+        }))
+    }
+}
+
+async fn accept_link(
+    stream: Box<dyn Stream>,
+    token: Arc<String>,
+    rooms: Rooms,
+    handshake_timeout: Duration,
+) {
+    let (mut lines, mut writer) = Lines::new(stream);
+
+    //A server that never says hello, or never reads the welcome, doesn't hold on to the task
+    let handshake = time::timeout(
+        handshake_timeout,
+        welcome(&mut lines, &mut writer, &token, &rooms),
+    );
+
+    let (hello, room) = match handshake.await {
+        Ok(Some(linked)) => linked,
+        Ok(None) => return,
+        Err(_) => return warn!("Link did not finish its handshake in time"),
+    };
+
+    //The linked room shows up in room_senders like a room of this process
+    let (proxy, mut outgoing) = unbounded_channel::<protocol::Room>();
+    let _ = room.send(protocol::Room::ConnectRoom(hello.from.clone(), proxy));
+    info!(from = %hello.from, to = %hello.to, "Room linked");
+
+    pump(&mut lines, &mut writer, &mut outgoing, &room, &hello.from).await;
+
+    drop(outgoing);
+    let _ = room.send(protocol::Room::DisconnectRoom(hello.from.clone()));
+    info!(from = %hello.from, to = %hello.to, "Room unlinked");
+}
+
+//Reads the hello and answers it, the room to link comes back when the link was accepted
+async fn welcome(
+    lines: &mut Lines,
+    writer: &mut Writer,
+    token: &str,
+    rooms: &Rooms,
+) -> Option<(Hello, UnboundedSender<protocol::Room>)> {
+    let hello = match lines.next_line().await {
+        Ok(Some(line)) => serde_json::from_str::<Hello>(&line).ok(),
+        _ => None,
+    };
+
+    let hello = match hello {
+        Some(hello) => hello,
+        None => {
+            debug!("Link closed before saying hello");
+            return None;
+        }
+    };
+
+    //The linked room is known by its name, which can't be taken from a room of this server
+    let room = match rooms.get(&hello.to) {
+        _ if !same_token(&hello.token, token) => Err("The token is not valid".to_string()),
+        _ if rooms.contains_key(&hello.from) => {
+            Err(format!("The room {} is a room of this server", hello.from))
+        }
+        Some(room) if !room.is_closed() => Ok(room.clone()),
+        Some(_) => Err(format!("The room {} is no longer running", hello.to)),
+        None => Err(format!("The room {} does not exist", hello.to)),
+    };
+
+    let welcome = Welcome {
+        ok: room.is_ok(),
+        error: room.as_ref().err().cloned(),
+    };
+
+    write_line(writer, &welcome).await.ok()?;
+
+    match room {
+        Ok(room) => Some((hello, room)),
+        Err(error) => {
+            warn!(from = %hello.from, %error, "Room link refused");
+            None
+        }
+    }
+}
+
+//A room on another server, reached through its FederationListener
+#[derive(Clone, Debug)]
+pub struct RemoteRoom {
+    pub addr: String,
+    pub room: String,
+    pub token: String,
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsClientConfig>,
+}
+
+impl RemoteRoom {
+    pub fn new(addr: &str, room: &str, token: &str) -> Self {
+        Self {
+            addr: String::from(addr),
+            room: String::from(room),
+            token: String::from(token),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    //The link goes over tls, for a FederationListener with a certificate
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: TlsClientConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+}
+
+//Keeps the link of a local room to a remote one up until the local room stops
+pub(crate) async fn open_link(
+    namespace: String,
+    room: UnboundedSender<protocol::Room>,
+    remote: RemoteRoom,
+    mut outgoing: UnboundedReceiver<protocol::Room>,
+) {
+    let mut backoff = MIN_RECONNECT_BACKOFF;
+
+    while !room.is_closed() {
+        match connect(&namespace, &remote).await {
+            Ok((mut lines, mut writer)) => {
+                backoff = MIN_RECONNECT_BACKOFF;
+                info!(addr = %remote.addr, "Room linked");
+
+                //Only returns false once this room stopped
+                if !pump(&mut lines, &mut writer, &mut outgoing, &room, &remote.room).await {
+                    return;
+                }
+
+                warn!(addr = %remote.addr, "Room link lost");
+            }
+
+            Err(error) => warn!(addr = %remote.addr, %error, ?backoff, "Failed to link the room"),
+        }
+
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+}
+
+async fn connect(
+    namespace: &str,
+    remote: &RemoteRoom,
+) -> std::result::Result<(Lines, Writer), String> {
+    let stream = TcpStream::connect(&remote.addr)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    #[cfg(feature = "tls")]
+    let stream: Box<dyn Stream> = match &remote.tls {
+        Some(tls) => Box::new(
+            tls.connect(stream)
+                .await
+                .map_err(|error| error.to_string())?,
+        ),
+
+        None => Box::new(stream),
+    };
+
+    #[cfg(not(feature = "tls"))]
+    let stream: Box<dyn Stream> = Box::new(stream);
+
+    let (mut lines, mut writer) = Lines::new(stream);
+
+    let hello = Hello {
+        token: remote.token.clone(),
+        from: namespace.to_string(),
+        to: remote.room.clone(),
+    };
+
+    write_line(&mut writer, &hello)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    let welcome = match lines.next_line().await {
+        Ok(Some(line)) => {
+            serde_json::from_str::<Welcome>(&line).map_err(|error| error.to_string())?
+        }
+        Ok(None) => return Err("The link closed during the handshake".to_string()),
+        Err(error) => return Err(error.to_string()),
+    };
+
+    match welcome {
+        Welcome { ok: true, .. } => Ok((lines, writer)),
+        Welcome { error, .. } => Err(error.unwrap_or_default()),
+    }
+}
+
+//Moves events both ways until the link drops, returns false when the local side is gone for good
+async fn pump(
+    lines: &mut Lines,
+    writer: &mut Writer,
+    outgoing: &mut UnboundedReceiver<protocol::Room>,
+    room: &UnboundedSender<protocol::Room>,
+    remote_name: &str,
+) -> bool {
+    loop {
+        tokio::select! {
+            command = outgoing.recv() => match command {
+                Some(protocol::Room::Event(event, data, _)) => {
+                    if write_line(writer, &Frame { event, data }).await.is_err() {
+                        return true;
+                    }
+                }
+
+                //Rooms only send events to each other
+                Some(_) => {}
+
+                None => return false,
+            },
+
+            line = lines.next_line() => {
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    Ok(None) => return true,
+
+                    Err(error) => {
+                        warn!(%error, "Failed to read from the link");
+                        return true;
+                    }
+                };
+
+                let frame = match serde_json::from_str::<Frame>(&line) {
+                    Ok(frame) => frame,
+                    Err(error) => {
+                        debug!(%error, "Dropped a link frame that is not valid");
+                        continue;
+                    }
+                };
+
+                let emiter = protocol::Emiter::Room(remote_name.to_string());
+                if room.send(protocol::Room::Event(frame.event, frame.data, emiter)).is_err() {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+async fn write_line(writer: &mut Writer, value: &impl Serialize) -> io::Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    writer.write_all(&line).await
+}
+
+//Compares every byte so the time taken does not tell how much of the token was right
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
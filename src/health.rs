@@ -0,0 +1,209 @@
+use crate::{metrics, protocol::Room};
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc::UnboundedSender,
+};
+
+const MAX_HEAD_SIZE: usize = 8192;
+
+#[derive(Clone, Debug)]
+pub struct HealthPaths {
+    //Answers 200 as long as the listener is accepting connections
+    pub health: String,
+
+    //Answers 200 when every room is running and the listener is not draining, 503 otherwise
+    pub ready: String,
+
+    //Answers the prometheus text format of every roommate metric
+    pub metrics: Option<String>,
+}
+
+impl Default for HealthPaths {
+    fn default() -> Self {
+        Self {
+            health: String::from("/healthz"),
+            ready: String::from("/readyz"),
+            metrics: None,
+        }
+    }
+}
+
+pub struct Health {
+    rooms: Arc<HashMap<String, UnboundedSender<Room>>>,
+    draining: AtomicBool,
+}
+
+impl Health {
+    pub(crate) fn new(rooms: Arc<HashMap<String, UnboundedSender<Room>>>) -> Self {
+        Self {
+            rooms,
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    //While draining the listener answers readiness checks and new upgrades with 503,
+    //the users already connected stay until they leave
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    //A room channel is closed once its run loop has finished
+    pub fn rooms_alive(&self) -> bool {
+        self.rooms.values().all(|room| !room.is_closed())
+    }
+
+    pub fn is_ready(&self) -> bool {
+        !self.is_draining() && self.rooms_alive()
+    }
+
+    //Reads the head of the request and, if it is not a websocket upgrade, answers it here.
+    //Returns true when the connection was consumed by this function
+    pub(crate) async fn intercept<S>(&self, stream: &mut Rewind<S>, paths: &HealthPaths) -> bool
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let head = match stream.read_head().await {
+            Ok(head) => head,
+            Err(_) => return false,
+        };
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+
+        match request.parse(head) {
+            Ok(httparse::Status::Complete(_)) => {}
+            _ => return false,
+        }
+
+        let is_upgrade = request
+            .headers
+            .iter()
+            .any(|header| header.name.eq_ignore_ascii_case("upgrade"));
+
+        if is_upgrade {
+            return false;
+        }
+
+        let (status, body) = match request.path {
+            Some(path) if path == paths.health => ("200 OK", String::from("ok")),
+
+            Some(path) if path == paths.ready => match self.is_ready() {
+                true => ("200 OK", String::from("ready")),
+                false => ("503 Service Unavailable", String::from("not ready")),
+            },
+
+            Some(path) if paths.metrics.as_deref() == Some(path) => ("200 OK", metrics::render()),
+
+            _ => ("404 Not Found", String::from("not found")),
+        };
+
+        respond(stream, status, &body).await;
+
+        true
+    }
+}
+
+//Writes a whole plain http response and closes the stream
+pub(crate) async fn respond<S>(stream: &mut S, status: &str, body: &str)
+where
+    S: AsyncWrite + Unpin,
+{
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+//A stream that gives back the bytes already read from it before reading from the inner stream
+pub(crate) struct Rewind<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Rewind<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Self {
+            prefix: Vec::new(),
+            position: 0,
+            inner,
+        }
+    }
+
+    async fn read_head(&mut self) -> io::Result<&[u8]> {
+        let mut chunk = [0u8; 1024];
+
+        while !self.prefix.windows(4).any(|window| window == b"\r\n\r\n") {
+            if self.prefix.len() >= MAX_HEAD_SIZE {
+                break;
+            }
+
+            let read = self.inner.read(&mut chunk).await?;
+
+            if read == 0 {
+                break;
+            }
+
+            self.prefix.extend_from_slice(&chunk[..read]);
+        }
+
+        Ok(&self.prefix)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position < self.prefix.len() {
+            let remaining = &self.prefix[self.position..];
+            let amount = remaining.len().min(buf.remaining());
+
+            buf.put_slice(&remaining[..amount]);
+            self.position += amount;
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
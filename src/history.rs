@@ -0,0 +1,380 @@
+use crate::{error::Result, state::BoxFuture};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::warn;
+
+#[derive(Clone, Debug)]
+pub struct HistoryConfig {
+    //Most events kept, the oldest go first
+    pub max_entries: Option<usize>,
+
+    //Events older than this are dropped
+    pub max_age: Option<Duration>,
+
+    //Most bytes of event names and payloads kept
+    pub max_bytes: Option<usize>,
+
+    //Events that are recorded, every event sent to the users when None
+    pub events: Option<HashSet<String>>,
+
+    //Sends the whole history to every user that joins
+    pub replay_on_join: bool,
+
+    //Most entries returned by one history request
+    pub page_size: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: Some(100),
+            max_age: None,
+            max_bytes: None,
+            events: None,
+            replay_on_join: false,
+            page_size: 100,
+        }
+    }
+}
+
+impl HistoryConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn events(mut self, events: &[&str]) -> Self {
+        self.events = Some(events.iter().map(|event| event.to_string()).collect());
+        self
+    }
+
+    pub fn replay_on_join(mut self, replay_on_join: bool) -> Self {
+        self.replay_on_join = replay_on_join;
+        self
+    }
+
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub seq: u64,
+    pub event: String,
+    pub payload: Value,
+    pub time: SystemTime,
+    recorded_at: Instant,
+    size: usize,
+}
+
+impl Entry {
+    pub fn new(seq: u64, event: String, payload: Value, time: SystemTime) -> Self {
+        //Entries loaded from a store keep their age, as far as the clock allows
+        let age = time.elapsed().unwrap_or_default();
+        let now = Instant::now();
+        let recorded_at = now.checked_sub(age).unwrap_or(now);
+        let size = event.len() + payload.to_string().len();
+
+        Self {
+            seq,
+            event,
+            payload,
+            time,
+            recorded_at,
+            size,
+        }
+    }
+}
+
+//Keeps the history of the rooms outside of the process
+pub trait HistoryStore: Send + Sync {
+    //Saves the entry, keeping only the newest entries of the room when keep is given
+    fn append<'a>(
+        &'a self,
+        namespace: &'a str,
+        entry: Entry,
+        keep: Option<usize>,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    //The newest entries of the room, oldest first
+    fn recent<'a>(&'a self, namespace: &'a str, limit: usize) -> BoxFuture<'a, Result<Vec<Entry>>>;
+}
+
+impl From<Entry> for Value {
+    fn from(entry: Entry) -> Self {
+        json!({"seq": entry.seq, "event": entry.event, "data": entry.payload})
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: VecDeque<Entry>,
+    bytes: usize,
+    last_seq: u64,
+}
+
+//The recent events a room sent to its users, numbered so clients can ask for what they missed.
+//In a cluster every node records the relayed events too, but numbers them on its own
+pub struct History {
+    pub config: HistoryConfig,
+    entries: Mutex<Entries>,
+
+    //Where every recorded entry is also written, with the namespace of the room
+    store: Option<(String, Arc<dyn HistoryStore>)>,
+
+    //Recorded entries on their way to the store, the writer takes them one at a time so they land in order
+    writes: Option<UnboundedSender<Entry>>,
+    writer: Mutex<Option<UnboundedReceiver<Entry>>>,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(Entries::default()),
+            store: None,
+            writes: None,
+            writer: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn persist(&mut self, namespace: String, store: Arc<dyn HistoryStore>) {
+        let (writes, writer) = unbounded_channel();
+
+        self.store = Some((namespace, store));
+        self.writes = Some(writes);
+        self.writer = Mutex::new(Some(writer));
+    }
+
+    //Loads what the store kept from a previous run, so sequence numbers carry on from there,
+    //then starts writing the new entries
+    pub(crate) async fn restore(&self) -> Result<usize> {
+        let (namespace, store) = match &self.store {
+            Some(store) => store,
+            None => return Ok(0),
+        };
+
+        let limit = self.config.max_entries.unwrap_or(usize::MAX);
+        let restored = store.recent(namespace, limit).await;
+
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            let keep = self.config.max_entries;
+            tokio::spawn(write(namespace.clone(), store.clone(), writer, keep));
+        }
+
+        let restored = restored?;
+
+        let mut entries = self.entries.lock().unwrap();
+
+        for entry in restored {
+            if entry.seq <= entries.last_seq {
+                continue;
+            }
+
+            entries.last_seq = entry.seq;
+            entries.bytes += entry.size;
+            entries.entries.push_back(entry);
+        }
+
+        self.prune(&mut entries);
+
+        Ok(entries.entries.len())
+    }
+
+    //Sequence number of the newest event, 0 if nothing was recorded yet
+    pub fn last_seq(&self) -> u64 {
+        self.entries.lock().unwrap().last_seq
+    }
+
+    //Records the event if the config asks for it, returning its sequence number
+    pub fn record(&self, event: &str, payload: &Value) -> Option<u64> {
+        if let Some(events) = &self.config.events {
+            if !events.contains(event) {
+                return None;
+            }
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.last_seq += 1;
+
+        let entry = Entry::new(
+            entries.last_seq,
+            event.to_string(),
+            payload.clone(),
+            SystemTime::now(),
+        );
+
+        //Written in the background, so recording never waits on the store
+        if let Some(writes) = &self.writes {
+            let _ = writes.send(entry.clone());
+        }
+
+        entries.bytes += entry.size;
+        entries.entries.push_back(entry);
+        self.prune(&mut entries);
+
+        Some(entries.last_seq)
+    }
+
+    //Entries newer than the sequence number, at most limit of them, and whether more are left
+    pub fn since(&self, seq: u64, limit: usize) -> (Vec<Entry>, bool) {
+        let mut entries = self.entries.lock().unwrap();
+        self.prune(&mut entries);
+
+        let mut newer = entries.entries.iter().filter(|entry| entry.seq > seq);
+        let page: Vec<Entry> = newer.by_ref().take(limit).cloned().collect();
+        let more = newer.next().is_some();
+
+        (page, more)
+    }
+
+    pub fn entries(&self) -> Vec<Entry> {
+        let mut entries = self.entries.lock().unwrap();
+        self.prune(&mut entries);
+
+        entries.entries.iter().cloned().collect()
+    }
+
+    fn prune(&self, entries: &mut Entries) {
+        loop {
+            let oldest = match entries.entries.front() {
+                Some(oldest) => oldest,
+                None => return,
+            };
+
+            let too_many =
+                matches!(self.config.max_entries, Some(max) if entries.entries.len() > max);
+            let too_big = matches!(self.config.max_bytes, Some(max) if entries.bytes > max);
+            let too_old =
+                matches!(self.config.max_age, Some(max) if oldest.recorded_at.elapsed() > max);
+
+            if !(too_many || too_big || too_old) {
+                return;
+            }
+
+            let size = oldest.size;
+            entries.entries.pop_front();
+            entries.bytes -= size;
+        }
+    }
+}
+
+//Saves the entries in the order they were recorded, it stops once the history is dropped
+async fn write(
+    namespace: String,
+    store: Arc<dyn HistoryStore>,
+    mut writer: UnboundedReceiver<Entry>,
+    keep: Option<usize>,
+) {
+    while let Some(entry) = writer.recv().await {
+        if let Err(error) = store.append(&namespace, entry, keep).await {
+            warn!(room = %namespace, %error, "Failed to save a history entry");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn recorded(history: &History, count: u64) {
+        for seq in 1..=count {
+            assert_eq!(history.record("e", &json!(seq * 1000)), Some(seq));
+        }
+    }
+
+    fn seqs(entries: &[Entry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.seq).collect()
+    }
+
+    #[test]
+    fn the_oldest_entries_go_over_the_count() {
+        let history = History::new(HistoryConfig::new().max_entries(3));
+        recorded(&history, 5);
+
+        assert_eq!(seqs(&history.entries()), vec![3, 4, 5]);
+        assert_eq!(history.last_seq(), 5);
+    }
+
+    #[test]
+    fn the_oldest_entries_go_over_the_bytes() {
+        //"e" and a four digit payload make five bytes an entry
+        let config = HistoryConfig {
+            max_entries: None,
+            ..HistoryConfig::new().max_bytes(12)
+        };
+        let history = History::new(config);
+        recorded(&history, 3);
+
+        assert_eq!(seqs(&history.entries()), vec![2, 3]);
+    }
+
+    #[test]
+    fn old_entries_expire() {
+        let history = History::new(HistoryConfig::new().max_age(Duration::from_millis(50)));
+        recorded(&history, 2);
+
+        thread::sleep(Duration::from_millis(80));
+        history.record("e", &json!("new"));
+
+        assert_eq!(seqs(&history.entries()), vec![3]);
+    }
+
+    #[test]
+    fn only_the_configured_events_are_recorded() {
+        let history = History::new(HistoryConfig::new().events(&["message"]));
+
+        assert_eq!(history.record("typing", &json!({})), None);
+        assert_eq!(history.record("message", &json!({})), Some(1));
+        assert_eq!(history.last_seq(), 1);
+    }
+
+    #[test]
+    fn since_pages_through_the_newer_entries() {
+        let history = History::new(HistoryConfig::default());
+        recorded(&history, 5);
+
+        let (page, more) = history.since(0, 2);
+        assert_eq!((seqs(&page), more), (vec![1, 2], true));
+
+        let (page, more) = history.since(2, 2);
+        assert_eq!((seqs(&page), more), (vec![3, 4], true));
+
+        let (page, more) = history.since(4, 2);
+        assert_eq!((seqs(&page), more), (vec![5], false));
+
+        let (page, more) = history.since(5, 2);
+        assert_eq!((seqs(&page), more), (vec![], false));
+    }
+
+    #[test]
+    fn since_skips_what_was_pruned() {
+        let history = History::new(HistoryConfig::new().max_entries(2));
+        recorded(&history, 5);
+
+        let (page, more) = history.since(1, 10);
+        assert_eq!((seqs(&page), more), (vec![4, 5], false));
+    }
+}
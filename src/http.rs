@@ -0,0 +1,138 @@
+use crate::{limits::Limits, metrics::metrics, protocol, user::User};
+use http_body_util::Full;
+use hyper::{
+    body::Bytes, header, service::Service, upgrade, Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::{ready, Ready},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc::UnboundedSender,
+    task::JoinHandle,
+};
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::{handshake::derive_accept_key, protocol::Role};
+
+pub use crate::user::Rooms;
+
+//Runs a roommate user over a connection that already went through the websocket handshake
+pub async fn serve_upgraded<S>(stream: S, rooms: &Rooms, limits: Limits) -> JoinHandle<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let config = limits.websocket_config();
+    let ws = WebSocketStream::from_raw_socket(stream, Role::Server, Some(config)).await;
+    metrics().connections_accepted.inc();
+
+    User::new(ws, Arc::downgrade(rooms), limits).run()
+}
+
+//Answers a websocket upgrade request, once hyper hands over the connection it is served as a roommate user
+pub fn upgrade<B: Send + 'static>(
+    mut request: Request<B>,
+    rooms: &Rooms,
+    limits: &Limits,
+) -> Response<Full<Bytes>> {
+    let accept_key = match accept_key(&request) {
+        Some(accept_key) => accept_key,
+        None => {
+            metrics().connections_rejected.inc();
+            return status(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    let on_upgrade = upgrade::on(&mut request);
+    let rooms = rooms.clone();
+    let limits = limits.clone();
+
+    tokio::spawn(async move {
+        if let Ok(upgraded) = on_upgrade.await {
+            serve_upgraded(TokioIo::new(upgraded), &rooms, limits).await;
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept_key)
+        .body(Full::default())
+        .unwrap()
+}
+
+pub fn is_upgrade_request<B>(request: &Request<B>) -> bool {
+    header_contains(request, header::CONNECTION, "upgrade")
+        && header_contains(request, header::UPGRADE, "websocket")
+}
+
+fn accept_key<B>(request: &Request<B>) -> Option<String> {
+    if request.method() != Method::GET || !is_upgrade_request(request) {
+        return None;
+    }
+
+    if !header_contains(request, header::SEC_WEBSOCKET_VERSION, "13") {
+        return None;
+    }
+
+    let key = request.headers().get(header::SEC_WEBSOCKET_KEY)?;
+    Some(derive_accept_key(key.as_bytes()))
+}
+
+fn header_contains<B>(request: &Request<B>, name: header::HeaderName, value: &str) -> bool {
+    request
+        .headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case(value))
+}
+
+pub(crate) fn status(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(Full::default())
+        .unwrap()
+}
+
+//A hyper service that upgrades requests on one path and answers 404 to everything else
+#[derive(Clone)]
+pub struct WebSocketService {
+    pub path: String,
+    pub rooms: Rooms,
+    pub limits: Limits,
+}
+
+impl WebSocketService {
+    pub fn new(path: &str, rooms: HashMap<String, UnboundedSender<protocol::Room>>) -> Self {
+        Self {
+            path: String::from(path),
+            rooms: Arc::new(rooms),
+            limits: Limits::default(),
+        }
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+
+impl<B: Send + 'static> Service<Request<B>> for WebSocketService {
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Infallible>>;
+
+    fn call(&self, request: Request<B>) -> Self::Future {
+        if request.uri().path() != self.path {
+            return ready(Ok(status(StatusCode::NOT_FOUND)));
+        }
+
+        ready(Ok(upgrade(request, &self.rooms, &self.limits)))
+    }
+}
@@ -1,11 +1,38 @@
+pub mod client;
+pub mod cluster;
 pub mod connection;
 pub mod data;
+pub mod dispatch;
+pub mod error;
 pub mod event;
+pub mod fanout;
+pub mod federation;
+pub mod health;
+pub mod history;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod limits;
+mod lines;
+pub mod metrics;
+#[cfg(feature = "http")]
+pub mod polling;
 pub mod prelude;
 pub mod protocol;
 pub mod room;
 mod room_builder;
-mod user;
+pub mod session;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "http")]
+pub mod sse;
+pub mod state;
+#[cfg(feature = "testkit")]
+pub mod testkit;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod user;
+
+pub use error::{Error, Result};
 
 #[macro_export]
 macro_rules! connect_rooms {
@@ -0,0 +1,371 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+use tungstenite::protocol::WebSocketConfig;
+
+#[derive(Clone, Debug)]
+pub struct Limits {
+    //Biggest websocket frame accepted from a client, in bytes
+    pub max_frame_size: Option<usize>,
+
+    //Biggest complete (possibly fragmented) message accepted from a client, in bytes
+    pub max_message_size: Option<usize>,
+
+    //How many arrays/objects can be nested inside an incoming json
+    pub max_json_depth: usize,
+
+    //Longest event name accepted in an incoming message
+    pub max_event_name_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: Some(1 << 20),
+            max_message_size: Some(1 << 20),
+            max_json_depth: 32,
+            max_event_name_len: 128,
+        }
+    }
+}
+
+impl Limits {
+    pub(crate) fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig::default()
+            .max_frame_size(self.max_frame_size)
+            .max_message_size(self.max_message_size)
+    }
+
+    //Walk the raw text counting brackets outside of strings, so a hostile payload
+    //is rejected before serde builds anything out of it
+    pub(crate) fn exceeds_json_depth(&self, text: &str) -> bool {
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+
+        for byte in text.bytes() {
+            if in_string {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+
+                continue;
+            }
+
+            match byte {
+                b'"' => in_string = true,
+
+                b'[' | b'{' => {
+                    depth += 1;
+
+                    if depth > self.max_json_depth {
+                        return true;
+                    }
+                }
+
+                b']' | b'}' => depth = depth.saturating_sub(1),
+
+                _ => {}
+            }
+        }
+
+        false
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionLimits {
+    //Concurrent connections accepted by the listener
+    pub max_connections: Option<usize>,
+
+    //Concurrent connections accepted from a single remote ip
+    pub max_per_ip: Option<usize>,
+
+    //Concurrent connections accepted for a single identity, see SocketListener::identify
+    pub max_per_identity: Option<usize>,
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_identity: HashMap<String, usize>,
+}
+
+//Keeps count of the open connections so new ones can be checked against the limits
+#[derive(Default)]
+pub struct ConnectionTracker {
+    limits: ConnectionLimits,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Rejection {
+    Total,
+    Ip,
+    Identity,
+    Draining,
+}
+
+impl Rejection {
+    pub(crate) fn status(&self) -> (u16, &'static str) {
+        match self {
+            Rejection::Total | Rejection::Draining => (503, "Service Unavailable"),
+            Rejection::Ip | Rejection::Identity => (429, "Too Many Requests"),
+        }
+    }
+
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            Rejection::Total => "The server is at its connection limit",
+            Rejection::Ip => "Too many connections from this address",
+            Rejection::Identity => "Too many connections for this identity",
+            Rejection::Draining => "The server is draining",
+        }
+    }
+}
+
+impl ConnectionTracker {
+    pub(crate) fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            counts: Mutex::new(Counts::default()),
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        self.counts.lock().unwrap().total
+    }
+
+    pub fn active_for_ip(&self, ip: IpAddr) -> usize {
+        let counts = self.counts.lock().unwrap();
+        counts.per_ip.get(&ip).copied().unwrap_or_default()
+    }
+
+    pub fn active_for_identity(&self, identity: &str) -> usize {
+        let counts = self.counts.lock().unwrap();
+        counts
+            .per_identity
+            .get(identity)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    //Counts a new connection from this ip, the returned guard releases it when dropped
+    pub(crate) fn acquire(
+        self: &Arc<Self>,
+        ip: Option<IpAddr>,
+    ) -> Result<ConnectionGuard, Rejection> {
+        let mut counts = self.counts.lock().unwrap();
+
+        if reached(self.limits.max_connections, counts.total) {
+            return Err(Rejection::Total);
+        }
+
+        if let Some(ip) = ip {
+            let per_ip = counts.per_ip.get(&ip).copied().unwrap_or_default();
+
+            if reached(self.limits.max_per_ip, per_ip) {
+                return Err(Rejection::Ip);
+            }
+
+            counts.per_ip.insert(ip, per_ip + 1);
+        }
+
+        counts.total += 1;
+
+        Ok(ConnectionGuard {
+            tracker: self.clone(),
+            ip,
+            identity: None,
+        })
+    }
+}
+
+fn reached(limit: Option<usize>, count: usize) -> bool {
+    matches!(limit, Some(max) if count >= max)
+}
+
+pub(crate) struct ConnectionGuard {
+    tracker: Arc<ConnectionTracker>,
+    ip: Option<IpAddr>,
+    identity: Option<String>,
+}
+
+impl ConnectionGuard {
+    pub(crate) fn identify(&mut self, identity: String) -> Result<(), Rejection> {
+        let mut counts = self.tracker.counts.lock().unwrap();
+        let per_identity = counts
+            .per_identity
+            .get(&identity)
+            .copied()
+            .unwrap_or_default();
+
+        if reached(self.tracker.limits.max_per_identity, per_identity) {
+            return Err(Rejection::Identity);
+        }
+
+        counts
+            .per_identity
+            .insert(identity.clone(), per_identity + 1);
+        self.identity = Some(identity);
+
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.tracker.counts.lock().unwrap();
+        counts.total -= 1;
+
+        if let Some(ip) = &self.ip {
+            if let Some(per_ip) = counts.per_ip.get_mut(ip) {
+                *per_ip -= 1;
+
+                if *per_ip == 0 {
+                    counts.per_ip.remove(ip);
+                }
+            }
+        }
+
+        if let Some(identity) = &self.identity {
+            if let Some(per_identity) = counts.per_identity.get_mut(identity) {
+                *per_identity -= 1;
+
+                if *per_identity == 0 {
+                    counts.per_identity.remove(identity);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const BOB: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    fn tracker(limits: ConnectionLimits) -> Arc<ConnectionTracker> {
+        Arc::new(ConnectionTracker::new(limits))
+    }
+
+    #[test]
+    fn the_total_limit_counts_every_connection() {
+        let tracker = tracker(ConnectionLimits {
+            max_connections: Some(2),
+            ..ConnectionLimits::default()
+        });
+
+        let first = tracker.acquire(Some(ALICE)).unwrap();
+        let _second = tracker.acquire(None).unwrap();
+
+        assert!(matches!(tracker.acquire(Some(BOB)), Err(Rejection::Total)));
+        assert_eq!(tracker.active_for_ip(BOB), 0);
+
+        drop(first);
+        assert!(tracker.acquire(Some(BOB)).is_ok());
+    }
+
+    #[test]
+    fn the_ip_limit_only_counts_that_ip() {
+        let tracker = tracker(ConnectionLimits {
+            max_per_ip: Some(1),
+            ..ConnectionLimits::default()
+        });
+
+        let _alice = tracker.acquire(Some(ALICE)).unwrap();
+
+        assert!(matches!(tracker.acquire(Some(ALICE)), Err(Rejection::Ip)));
+        assert!(tracker.acquire(Some(BOB)).is_ok());
+        assert_eq!(tracker.active(), 1);
+    }
+
+    #[test]
+    fn the_identity_limit_is_checked_when_identified() {
+        let tracker = tracker(ConnectionLimits {
+            max_per_identity: Some(1),
+            ..ConnectionLimits::default()
+        });
+
+        let mut first = tracker.acquire(Some(ALICE)).unwrap();
+        let mut second = tracker.acquire(Some(BOB)).unwrap();
+
+        assert!(first.identify(String::from("alice")).is_ok());
+        assert!(matches!(
+            second.identify(String::from("alice")),
+            Err(Rejection::Identity)
+        ));
+        assert!(second.identify(String::from("bob")).is_ok());
+        assert_eq!(tracker.active_for_identity("alice"), 1);
+    }
+
+    #[test]
+    fn dropped_guards_release_every_count() {
+        let tracker = tracker(ConnectionLimits::default());
+
+        let mut guards = Vec::new();
+
+        for identity in ["alice", "alice", "bob"] {
+            let mut guard = tracker.acquire(Some(ALICE)).unwrap();
+            guard.identify(String::from(identity)).unwrap();
+            guards.push(guard);
+        }
+
+        guards.push(tracker.acquire(None).unwrap());
+
+        assert_eq!(tracker.active(), 4);
+        assert_eq!(tracker.active_for_ip(ALICE), 3);
+        assert_eq!(tracker.active_for_identity("alice"), 2);
+
+        drop(guards);
+
+        assert_eq!(tracker.active(), 0);
+        assert_eq!(tracker.active_for_ip(ALICE), 0);
+        assert_eq!(tracker.active_for_identity("alice"), 0);
+        assert_eq!(tracker.active_for_identity("bob"), 0);
+
+        let counts = tracker.counts.lock().unwrap();
+        assert!(counts.per_ip.is_empty() && counts.per_identity.is_empty());
+    }
+
+    fn limits(max_json_depth: usize) -> Limits {
+        Limits {
+            max_json_depth,
+            ..Limits::default()
+        }
+    }
+
+    #[test]
+    fn nesting_at_the_limit_passes() {
+        assert!(!limits(3).exceeds_json_depth(r#"{"a": [{"b": 1}]}"#));
+    }
+
+    #[test]
+    fn nesting_over_the_limit_is_rejected() {
+        assert!(limits(3).exceeds_json_depth(r#"{"a": [{"b": [1]}]}"#));
+    }
+
+    #[test]
+    fn siblings_do_not_add_up() {
+        assert!(!limits(2).exceeds_json_depth(r#"[[1], [2], {"a": 3}]"#));
+    }
+
+    #[test]
+    fn brackets_inside_strings_are_ignored() {
+        assert!(!limits(1).exceeds_json_depth(r#"{"text": "[[[{{{"}"#));
+    }
+
+    #[test]
+    fn escaped_quotes_do_not_end_strings() {
+        assert!(!limits(1).exceeds_json_depth(r#"{"text": "say \"[[[\" twice"}"#));
+        assert!(limits(1).exceeds_json_depth(r#"{"text": "ends with \\", "a": [1]}"#));
+    }
+}
@@ -0,0 +1,58 @@
+use std::io;
+use tokio::io::{
+    split, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, BufReader, ReadHalf, WriteHalf,
+};
+
+//Longest line a link reads, a longer one drops the link
+const MAX_LINE_SIZE: usize = 1 << 20;
+
+//The links between servers and nodes run over tcp or over tls
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+pub(crate) type Writer = WriteHalf<Box<dyn Stream>>;
+
+//Reads the link a line at a time, a line cut short by select is finished on the next call
+pub(crate) struct Lines {
+    reader: BufReader<ReadHalf<Box<dyn Stream>>>,
+    line: Vec<u8>,
+}
+
+impl Lines {
+    pub(crate) fn new(stream: Box<dyn Stream>) -> (Self, Writer) {
+        let (reader, writer) = split(stream);
+
+        let lines = Self {
+            reader: BufReader::new(reader),
+            line: Vec::new(),
+        };
+
+        (lines, writer)
+    }
+
+    //None once the link closed
+    pub(crate) async fn next_line(&mut self) -> io::Result<Option<String>> {
+        let left = (MAX_LINE_SIZE + 1 - self.line.len()) as u64;
+        let read = (&mut self.reader)
+            .take(left)
+            .read_until(b'\n', &mut self.line)
+            .await?;
+
+        if self.line.last() != Some(&b'\n') {
+            return match read {
+                0 if self.line.is_empty() => Ok(None),
+                0 => Err(io::ErrorKind::UnexpectedEof.into()),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The line is too long",
+                )),
+            };
+        }
+
+        let line = std::mem::take(&mut self.line);
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
@@ -0,0 +1,122 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub struct Metrics {
+    pub registry: Registry,
+    pub connections_accepted: IntCounter,
+    pub connections_rejected: IntCounter,
+    pub active_users: IntGauge,
+    pub room_members: IntGaugeVec,
+    pub messages_in: IntCounterVec,
+    pub messages_out: IntCounterVec,
+    pub events_lagged: IntCounterVec,
+    pub handler_latency: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some(String::from("roommate")), None).unwrap();
+
+        let connections_accepted = IntCounter::new(
+            "connections_accepted_total",
+            "Connections that completed the websocket handshake",
+        )
+        .unwrap();
+
+        let connections_rejected = IntCounter::new(
+            "connections_rejected_total",
+            "Connections dropped before becoming a user",
+        )
+        .unwrap();
+
+        let active_users = IntGauge::new("active_users", "Users currently connected").unwrap();
+
+        let room_members = IntGaugeVec::new(
+            Opts::new("room_members", "Users currently connected to a room"),
+            &["room"],
+        )
+        .unwrap();
+
+        let messages_in = IntCounterVec::new(
+            Opts::new("messages_in_total", "Events received by a room"),
+            &["room", "event"],
+        )
+        .unwrap();
+
+        let messages_out = IntCounterVec::new(
+            Opts::new("messages_out_total", "Events sent by a room to its users"),
+            &["room", "event"],
+        )
+        .unwrap();
+
+        let events_lagged = IntCounterVec::new(
+            Opts::new(
+                "events_lagged_total",
+                "Events of a ring room that its users missed by falling behind",
+            ),
+            &["room"],
+        )
+        .unwrap();
+
+        let handler_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "handler_duration_seconds",
+                "Time spent running an event handler",
+            ),
+            &["room", "event"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(connections_accepted.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connections_rejected.clone()))
+            .unwrap();
+        registry.register(Box::new(active_users.clone())).unwrap();
+        registry.register(Box::new(room_members.clone())).unwrap();
+        registry.register(Box::new(messages_in.clone())).unwrap();
+        registry.register(Box::new(messages_out.clone())).unwrap();
+        registry.register(Box::new(events_lagged.clone())).unwrap();
+        registry
+            .register(Box::new(handler_latency.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            connections_accepted,
+            connections_rejected,
+            active_users,
+            room_members,
+            messages_in,
+            messages_out,
+            events_lagged,
+            handler_latency,
+        }
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+//Every roommate metric, to be gathered together with the application ones
+pub fn registry() -> &'static Registry {
+    &metrics().registry
+}
+
+//The current metrics in the prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+
+    TextEncoder::new()
+        .encode(&registry().gather(), &mut buffer)
+        .unwrap();
+
+    String::from_utf8(buffer).unwrap()
+}
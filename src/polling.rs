@@ -0,0 +1,368 @@
+use crate::{
+    http::{status, Rooms},
+    limits::{ConnectionGuard, ConnectionLimits, ConnectionTracker, Limits},
+    metrics::metrics,
+    protocol,
+    user::{ChannelTransport, User},
+};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Body, Bytes},
+    header,
+    service::Service,
+    Method, Request, Response, StatusCode,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        Mutex as AsyncMutex,
+    },
+    time,
+};
+use tracing::{debug, info, warn};
+use tungstenite::Message;
+use uuid::Uuid;
+
+type Sessions = Arc<Mutex<HashMap<String, PollSession>>>;
+
+struct PollSession {
+    //Frames posted by the client go here, dropping it ends the user
+    inbound: UnboundedSender<Message>,
+
+    //Frames for the client wait here until the next poll
+    outbound: Arc<AsyncMutex<UnboundedReceiver<Message>>>,
+
+    last_seen: Instant,
+
+    //Counts the session against the connection limits for as long as it lives
+    _guard: ConnectionGuard,
+}
+
+//Long polling transport for clients that can't open a websocket.
+//GET without a sid opens a session, GET with a sid waits for frames, POST with a sid sends
+//one frame or an array of frames and DELETE with a sid closes the session
+#[derive(Clone)]
+pub struct LongPolling {
+    pub path: String,
+    pub rooms: Rooms,
+    pub limits: Limits,
+
+    //How long a GET waits for frames before answering with an empty array
+    pub poll_timeout: Duration,
+
+    //How long a session lives without any request before its user is disconnected
+    pub session_timeout: Duration,
+
+    //Open sessions, checked against the limits the same way websocket connections are
+    pub connections: Arc<ConnectionTracker>,
+
+    sessions: Sessions,
+}
+
+impl LongPolling {
+    pub fn new(path: &str, rooms: HashMap<String, UnboundedSender<protocol::Room>>) -> Self {
+        Self {
+            path: String::from(path),
+            rooms: Arc::new(rooms),
+            limits: Limits::default(),
+            poll_timeout: Duration::from_secs(25),
+            session_timeout: Duration::from_secs(60),
+            connections: Arc::new(ConnectionTracker::default()),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn poll_timeout(mut self, timeout: Duration) -> Self {
+        self.poll_timeout = timeout;
+        self
+    }
+
+    pub fn session_timeout(mut self, timeout: Duration) -> Self {
+        self.session_timeout = timeout;
+        self
+    }
+
+    //The per ip limit needs the server to put the SocketAddr of the peer in the request extensions
+    pub fn connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.connections = Arc::new(ConnectionTracker::new(limits));
+        self
+    }
+
+    pub async fn handle<B>(&self, request: Request<B>) -> Response<Full<Bytes>>
+    where
+        B: Body,
+        B::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let sid = session_id(&request);
+
+        match (request.method(), sid) {
+            (&Method::GET, None) => self.open(peer_ip(&request)),
+            (&Method::GET, Some(sid)) => self.poll(&sid).await,
+            (&Method::POST, Some(sid)) => self.post(&sid, request.into_body()).await,
+            (&Method::DELETE, Some(sid)) => self.close(&sid),
+            (&Method::POST, None) | (&Method::DELETE, None) => status(StatusCode::BAD_REQUEST),
+            _ => status(StatusCode::METHOD_NOT_ALLOWED),
+        }
+    }
+
+    fn open(&self, ip: Option<IpAddr>) -> Response<Full<Bytes>> {
+        let guard = match self.connections.acquire(ip) {
+            Ok(guard) => guard,
+
+            Err(rejection) => {
+                warn!(reason = rejection.reason(), "Polling session rejected");
+                metrics().connections_rejected.inc();
+
+                let (code, _) = rejection.status();
+                let code = StatusCode::from_u16(code).unwrap();
+                return json_response(code, json!({ "error": rejection.reason() }));
+            }
+        };
+
+        let (transport, inbound, outbound) = ChannelTransport::pair();
+        let sid = Uuid::new_v4().to_string();
+
+        let session = PollSession {
+            inbound,
+            outbound: Arc::new(AsyncMutex::new(outbound)),
+            last_seen: Instant::now(),
+            _guard: guard,
+        };
+
+        self.sessions.lock().unwrap().insert(sid.clone(), session);
+        metrics().connections_accepted.inc();
+        info!(%sid, "Polling session opened");
+
+        User::new(transport, Arc::downgrade(&self.rooms), self.limits.clone()).run();
+        tokio::spawn(expire(
+            Arc::downgrade(&self.sessions),
+            sid.clone(),
+            self.session_timeout,
+        ));
+
+        json_response(StatusCode::OK, json!({ "sid": sid }))
+    }
+
+    async fn poll(&self, sid: &str) -> Response<Full<Bytes>> {
+        let outbound = match self.touch(sid) {
+            Some(session) => session.outbound.clone(),
+            None => return status(StatusCode::NOT_FOUND),
+        };
+
+        let mut frames = Vec::new();
+        let mut closed = false;
+
+        //A second poll on the same session waits for the first one to finish
+        let _ = time::timeout(self.poll_timeout, async {
+            let mut outbound = outbound.lock().await;
+
+            let mut next = outbound.recv().await;
+
+            //The user stopped, so nothing else will come out of this session
+            if next.is_none() {
+                closed = true;
+            }
+
+            while let Some(message) = next {
+                match message {
+                    Message::Text(text) => match serde_json::from_str::<Value>(&text) {
+                        Ok(frame) => frames.push(frame),
+                        Err(error) => debug!(%error, "Dropped a frame that is not json"),
+                    },
+
+                    Message::Close(_) => {
+                        closed = true;
+                        break;
+                    }
+
+                    _ => {}
+                }
+
+                next = outbound.try_recv().ok();
+            }
+        })
+        .await;
+
+        if closed {
+            self.remove(sid);
+        } else {
+            self.touch(sid);
+        }
+
+        json_response(StatusCode::OK, Value::Array(frames))
+    }
+
+    async fn post<B>(&self, sid: &str, body: B) -> Response<Full<Bytes>>
+    where
+        B: Body,
+        B::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let inbound = match self.touch(sid) {
+            Some(session) => session.inbound.clone(),
+            None => return status(StatusCode::NOT_FOUND),
+        };
+
+        let max_size = self.limits.max_message_size.unwrap_or(usize::MAX);
+        let body = match Limited::new(body, max_size).collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(_) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+        };
+
+        let text = match std::str::from_utf8(&body) {
+            Ok(text) => text,
+            Err(_) => return status(StatusCode::BAD_REQUEST),
+        };
+
+        //Checked before serde builds anything, the user checks every frame again
+        if self.limits.exceeds_json_depth(text) {
+            return status(StatusCode::BAD_REQUEST);
+        }
+
+        let frames = match serde_json::from_str::<Value>(text) {
+            Ok(Value::Array(frames)) => frames.iter().map(Value::to_string).collect(),
+
+            //Anything else goes to the user as is, so it answers with the same errors a websocket client gets
+            _ => vec![text.to_string()],
+        };
+
+        for frame in frames {
+            if inbound.send(Message::text(frame)).is_err() {
+                self.remove(sid);
+                return status(StatusCode::GONE);
+            }
+        }
+
+        status(StatusCode::NO_CONTENT)
+    }
+
+    fn close(&self, sid: &str) -> Response<Full<Bytes>> {
+        match self.remove(sid) {
+            Some(session) => {
+                let _ = session.inbound.send(Message::Close(None));
+                status(StatusCode::NO_CONTENT)
+            }
+
+            None => status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    //Marks the session as alive, returning what a request needs from it
+    fn touch(&self, sid: &str) -> Option<SessionHandle> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(sid)?;
+        session.last_seen = Instant::now();
+
+        Some(SessionHandle {
+            inbound: session.inbound.clone(),
+            outbound: session.outbound.clone(),
+        })
+    }
+
+    fn remove(&self, sid: &str) -> Option<PollSession> {
+        let session = self.sessions.lock().unwrap().remove(sid);
+
+        if session.is_some() {
+            info!(%sid, "Polling session closed");
+        }
+
+        session
+    }
+}
+
+struct SessionHandle {
+    inbound: UnboundedSender<Message>,
+    outbound: Arc<AsyncMutex<UnboundedReceiver<Message>>>,
+}
+
+//Removes the session once it goes a whole session timeout without requests
+async fn expire(
+    sessions: Weak<Mutex<HashMap<String, PollSession>>>,
+    sid: String,
+    timeout: Duration,
+) {
+    let mut deadline = Instant::now() + timeout;
+
+    loop {
+        time::sleep_until(deadline.into()).await;
+
+        let sessions = match sessions.upgrade() {
+            Some(sessions) => sessions,
+            None => return,
+        };
+
+        let mut sessions = sessions.lock().unwrap();
+
+        let last_seen = match sessions.get(&sid) {
+            Some(session) => session.last_seen,
+            None => return,
+        };
+
+        if last_seen.elapsed() >= timeout {
+            sessions.remove(&sid);
+            info!(%sid, "Polling session expired");
+            return;
+        }
+
+        deadline = last_seen + timeout;
+    }
+}
+
+fn peer_ip<B>(request: &Request<B>) -> Option<IpAddr> {
+    request
+        .extensions()
+        .get::<SocketAddr>()
+        .map(|addr| addr.ip())
+}
+
+fn session_id<B>(request: &Request<B>) -> Option<String> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("sid="))
+        .map(String::from)
+}
+
+fn json_response(code: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(code)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+impl<B> Service<Request<B>> for LongPolling
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn call(&self, request: Request<B>) -> Self::Future {
+        if request.uri().path() != self.path {
+            return Box::pin(async { Ok(status(StatusCode::NOT_FOUND)) });
+        }
+
+        let polling = self.clone();
+        Box::pin(async move { Ok(polling.handle(request).await) })
+    }
+}
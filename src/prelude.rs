@@ -1,8 +1,16 @@
 pub use crate::connection::SocketListener;
+pub use crate::dispatch::DispatchMode;
 pub use crate::event::{Event, EventMap};
+pub use crate::fanout::{FanOut, LagPolicy};
+pub use crate::health::HealthPaths;
+pub use crate::history::HistoryConfig;
+pub use crate::limits::{ConnectionLimits, Limits};
 pub use crate::protocol;
 pub use crate::room::Room;
 pub use crate::room_builder::RoomBuilder;
+pub use crate::state::{FileStore, MemoryStore, StateStore};
+#[cfg(feature = "tls")]
+pub use crate::tls::{TlsClientConfig, TlsConfig};
 pub use crate::{data, event, room};
 pub use futures_util::join;
 pub use serde_json::json;
@@ -1,41 +1,70 @@
+use crate::{cluster::Relay, fanout::Subscription, limits::Limits, room};
 use serde_json::{from_str, json, Value};
+use std::{fmt, sync::Arc};
 use tokio::sync::mpsc::UnboundedSender;
+use tungstenite::Message;
 use uuid::Uuid;
 
-#[derive(Clone)]
+//The events roommate sends and answers itself, the prefix keeps them apart from the events of an application
+pub const HISTORY_EVENT: &str = "roommate:history";
+pub const RESUME_EVENT: &str = "roommate:resume";
+pub const SESSION_EVENT: &str = "roommate:session";
+pub const LAGGED_EVENT: &str = "roommate:lagged";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Emiter {
     User(Uuid),
     Room(String),
 }
 
+#[derive(Debug)]
 pub enum Error {
+    BinaryNotSupported,
     EventIsNotAString,
+    EventNameTooLong,
     NeedMoreArguments,
     NotAJson,
     NoEventIncluded,
+    TooDeeplyNested,
 }
 
-impl Into<String> for Error {
-    fn into(self) -> String {
-        match self {
-            Self::NeedMoreArguments => "This command needs more argument to work".to_string(),
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Error::BinaryNotSupported => "Binary messages are not supported",
 
-            Self::EventIsNotAString => "The event property has to be a string".to_string(),
+            Error::NeedMoreArguments => "This command needs more argument to work",
 
-            Self::NoEventIncluded => "The data doesn't include a event property".to_string(),
+            Error::EventIsNotAString => "The event property has to be a string",
 
-            Self::NotAJson => "The data sended is not in a json format".to_string(),
-        }
+            Error::EventNameTooLong => "The event name is too long",
+
+            Error::NoEventIncluded => "The data doesn't include a event property",
+
+            Error::NotAJson => "The data sended is not in a json format",
+
+            Error::TooDeeplyNested => "The data sended is nested too deeply",
+        };
+
+        f.write_str(message)
     }
 }
 
-impl Into<Value> for Error {
-    fn into(self) -> Value {
-        json!({"event" : "error", "message": Into::<String>::into(self)})
+impl std::error::Error for Error {}
+
+impl From<Error> for String {
+    fn from(error: Error) -> Self {
+        error.to_string()
+    }
+}
+
+impl From<Error> for Value {
+    fn from(error: Error) -> Self {
+        json!({"event" : "error", "message": String::from(error)})
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum User {
     //Event of User
     Event(String, Value),
@@ -46,15 +75,40 @@ pub enum User {
     //Disconnect to room
     DisconnectRoom(String),
 
+    //Event of a room that keeps history, with the room name and its sequence number
+    Recorded(String, Value, String, u64),
+
+    //Ask a room for the recorded events after a sequence number, at most a limit of them
+    History(String, u64, Option<usize>),
+
+    //Take over a session parked after a lost connection
+    Resume(String),
+
+    //Event or Recorded going to many users, serialized once for all of them
+    Shared(Arc<Frame>),
+
+    //The ring to read the events of a room from, when the room fans out through one
+    Subscribe(Subscription),
+
     //Close User stream
     Close,
 }
 
-impl TryFrom<String> for User {
-    type Error = crate::protocol::Error;
+impl User {
+    //The command inside a shared frame, any other command as it is
+    pub fn unshare(self) -> Self {
+        match self {
+            User::Shared(frame) => frame.command.clone(),
+            command => command,
+        }
+    }
 
-    fn try_from(value: String) -> Result<Self, Error> {
-        let result = from_str::<Value>(&value);
+    pub fn parse(value: &str, limits: &Limits) -> Result<Self, Error> {
+        if limits.exceeds_json_depth(value) {
+            return Err(Error::TooDeeplyNested);
+        }
+
+        let result = from_str::<Value>(value);
 
         let json = match result {
             Ok(json) => json,
@@ -68,6 +122,10 @@ impl TryFrom<String> for User {
             _ => return Err(Error::EventIsNotAString),
         };
 
+        if event.len() > limits.max_event_name_len {
+            return Err(Error::EventNameTooLong);
+        }
+
         let user_protocol = match event.as_str() {
             "connect" | "disconnect" => {
                 let room = match &json["room"] {
@@ -83,6 +141,23 @@ impl TryFrom<String> for User {
                 }
             }
 
+            HISTORY_EVENT => {
+                let room = match &json["room"] {
+                    Value::String(room) => room.clone(),
+                    _ => return Err(Error::NeedMoreArguments),
+                };
+
+                let since = json["since"].as_u64().unwrap_or_default();
+                let limit = json["limit"].as_u64().map(|limit| limit as usize);
+
+                User::History(room, since, limit)
+            }
+
+            RESUME_EVENT => match &json["token"] {
+                Value::String(token) => User::Resume(token.clone()),
+                _ => return Err(Error::NeedMoreArguments),
+            },
+
             "close" => User::Close,
 
             event => {
@@ -95,17 +170,31 @@ impl TryFrom<String> for User {
             }
         };
 
-        return Ok(user_protocol);
+        Ok(user_protocol)
     }
 }
 
-impl Into<Value> for User {
-    fn into(self) -> Value {
-        match self {
+impl TryFrom<String> for User {
+    type Error = crate::protocol::Error;
+
+    fn try_from(value: String) -> Result<Self, Error> {
+        User::parse(&value, &Limits::default())
+    }
+}
+
+impl From<User> for Value {
+    fn from(user: User) -> Self {
+        match user {
             User::Event(event_name, data) => {
                 json!({"event": event_name, "data": data})
             }
 
+            User::Recorded(event_name, data, room, seq) => {
+                json!({"event": event_name, "data": data, "room": room, "seq": seq})
+            }
+
+            User::Shared(frame) => frame.command.clone().into(),
+
             User::Close => json!({"event": "close"}),
 
             _ => json!(null),
@@ -113,7 +202,26 @@ impl Into<Value> for User {
     }
 }
 
-#[derive(Clone)]
+//A command with the message the clients get for it. The text of a message is shared,
+//so every user task sends the same bytes
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub command: User,
+    pub message: Message,
+}
+
+impl Frame {
+    pub fn encode(command: User) -> Arc<Self> {
+        let message = Message::text(Value::from(command.clone()).to_string());
+
+        Arc::new(Self { command, message })
+    }
+
+    pub fn text(&self) -> &str {
+        self.message.to_text().unwrap_or_default()
+    }
+}
+
 pub enum Room {
     //Event of Room
     Event(String, Value, Emiter),
@@ -124,5 +232,23 @@ pub enum Room {
     //Disconnect User
     DisconnectUser(Uuid),
 
+    //Send the recorded events after a sequence number to a member
+    History(Uuid, u64, Option<usize>),
+
+    //Connect a room, like one linked from another server
+    ConnectRoom(String, UnboundedSender<Room>),
+
+    //Disconnect a room whose link is gone
+    DisconnectRoom(String),
+
+    //Something the same room on another process sent
+    Relayed(Relay),
+
+    //What the handle of the room asks its run loop, it shares the channel so it keeps its place among the rest
+    Order(Order),
+
     Close,
 }
+
+//Only the crate makes orders
+pub struct Order(pub(crate) room::Order);
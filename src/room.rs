@@ -1,52 +1,191 @@
 use crate::{
+    cluster::{Adapter, Relay},
+    dispatch::{DispatchMode, SenderQueues},
+    error::{Error, Result},
     event::{Event, EventMap},
-    protocol,
+    fanout::{FanOut, Ringed, Subscription},
+    federation::{open_link, RemoteRoom},
+    history::{Entry, History},
+    metrics::metrics,
+    protocol::{self, Frame},
+    state::RoomState,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Weak},
 };
-use serde_json::Value;
-use std::{collections::HashMap, sync::Arc};
 use tokio::{
     sync::{
-        mpsc::{UnboundedReceiver, UnboundedSender},
-        Mutex, RwLock,
+        broadcast,
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot, RwLock,
     },
     task::JoinHandle,
+    time,
 };
+use tracing::{debug, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
 pub struct Room {
     pub namespace: String,
     pub events: EventMap,
-    pub user_senders: RwLock<HashMap<Uuid, UnboundedSender<protocol::User>>>,
-    pub room_senders: RwLock<HashMap<String, UnboundedSender<protocol::Room>>>,
     pub sender: UnboundedSender<protocol::Room>,
-    pub receiver: Mutex<UnboundedReceiver<protocol::Room>>,
+    pub history: Option<History>,
+    pub state: Option<RoomState>,
+
+    //Relays the events of this room to the same room on other processes
+    pub adapter: Option<Arc<dyn Adapter>>,
+
+    pub dispatch: DispatchMode,
+    pub fan_out: FanOut,
+}
+
+//Something a handler asks the run loop to do, only the run loop sees the members of the room
+pub(crate) enum Order {
+    //An event for the users, the rooms or both, but the emiter
+    Emit {
+        event: String,
+        payload: Value,
+        users: bool,
+        rooms: bool,
+        except: Option<protocol::Emiter>,
+    },
+
+    Whisper(protocol::Emiter, String, Value, oneshot::Sender<Result<()>>),
+
+    Members(oneshot::Sender<Vec<Uuid>>),
+
+    RemoteMembers(oneshot::Sender<HashMap<Uuid, Uuid>>),
+
+    ConnectedRooms(oneshot::Sender<Vec<String>>),
+}
+
+//Everything the run loop owns, the members of the room included. It comes out of
+//RoomBuilder::build next to the room and goes to Room::run
+pub struct Actor {
+    receiver: UnboundedReceiver<protocol::Room>,
+    user_senders: HashMap<Uuid, UnboundedSender<protocol::User>>,
+    room_senders: HashMap<String, UnboundedSender<protocol::Room>>,
+
+    //Members connected to other processes, with the node they are on
+    remote_members: HashMap<Uuid, Uuid>,
+
+    sender_queues: SenderQueues,
+
+    //Events that arrived while a sequential handler was running, oldest first
+    pending: VecDeque<(String, Value, protocol::Emiter)>,
+
+    //Where the events for the users go when the room fans out through a ring
+    ring: Option<broadcast::Sender<Ringed>>,
 }
 
 impl Room {
+    pub(crate) fn new(
+        namespace: String,
+        events: EventMap,
+        room_senders: HashMap<String, UnboundedSender<protocol::Room>>,
+    ) -> (Self, Actor) {
+        let (sender, receiver) = unbounded_channel::<protocol::Room>();
+
+        let actor = Actor {
+            receiver,
+            user_senders: HashMap::new(),
+            room_senders,
+            remote_members: HashMap::new(),
+            sender_queues: SenderQueues::default(),
+            pending: VecDeque::new(),
+            ring: None,
+        };
+
+        let room = Self {
+            namespace,
+            events,
+            sender,
+            history: None,
+            state: None,
+            adapter: None,
+            dispatch: DispatchMode::default(),
+            fan_out: FanOut::default(),
+        };
+
+        (room, actor)
+    }
+
+    //The state given to RoomBuilder::state, None if the room has none or it is of another type
+    pub fn get_state<T: Send + Sync + 'static>(&self) -> Option<Arc<RwLock<T>>> {
+        self.state.as_ref()?.get::<T>()
+    }
+
+    //Saves the state now instead of waiting for the next snapshot
+    pub async fn snapshot(&self) -> Result<()> {
+        match &self.state {
+            Some(state) => state.save(&self.namespace).await,
+            None => Ok(()),
+        }
+    }
+
     pub async fn whisper(
         &self,
         emiter: protocol::Emiter,
         event: impl Into<String>,
         payload: Value,
-    ) {
-        match emiter {
-            protocol::Emiter::Room(room_name) => {
-                let room_senders = self.room_senders.read().await;
+    ) -> Result<()> {
+        let event: String = event.into();
 
-                let room_sender = room_senders.get(&room_name).unwrap();
+        self.ask(|reply| Order::Whisper(emiter, event, payload, reply))
+            .await?
+    }
 
-                let new_emiter = protocol::Emiter::Room(self.namespace.clone());
-                let _ = room_sender.send(protocol::Room::Event(event.into(), payload, new_emiter));
-            }
+    //Whispers the answer to a request of the user, with the request id the client is waiting on
+    pub async fn reply(
+        &self,
+        emiter: protocol::Emiter,
+        request: &Value,
+        event: impl Into<String>,
+        mut payload: Value,
+    ) -> Result<()> {
+        if let (Some(request_id), Some(payload)) =
+            (request.get("request_id"), payload.as_object_mut())
+        {
+            payload.insert("request_id".to_string(), request_id.clone());
+        }
 
-            protocol::Emiter::User(user_id) => {
-                let user_senders = self.user_senders.read().await;
+        self.whisper(emiter, event, payload).await
+    }
 
-                let user_sender = user_senders.get(&user_id).unwrap();
+    //The users connected to this room on this process
+    pub async fn members(&self) -> Result<Vec<Uuid>> {
+        self.ask(Order::Members).await
+    }
 
-                let _ = user_sender.send(protocol::User::Event(event.into(), payload));
-            }
-        }
+    //The users connected to this room on other processes, with the node they are on
+    pub async fn remote_members(&self) -> Result<HashMap<Uuid, Uuid>> {
+        self.ask(Order::RemoteMembers).await
+    }
+
+    //The rooms this room sends its events to, linked rooms of other servers included
+    pub async fn connected_rooms(&self) -> Result<Vec<String>> {
+        self.ask(Order::ConnectedRooms).await
+    }
+
+    //Connects this room to a room on another server, reconnecting until this room stops.
+    //Events for the remote room wait in its channel while the link is down
+    pub fn link(self: &Arc<Room>, remote: RemoteRoom) -> JoinHandle<()> {
+        let (proxy, outgoing) = unbounded_channel::<protocol::Room>();
+        let _ = self
+            .sender
+            .send(protocol::Room::ConnectRoom(remote.room.clone(), proxy));
+
+        let span = info_span!("link", room = %self.namespace, remote = %remote.room);
+        let link = open_link(
+            self.namespace.clone(),
+            self.sender.clone(),
+            remote,
+            outgoing,
+        );
+
+        tokio::spawn(link.instrument(span))
     }
 
     pub async fn emit_to_users(
@@ -55,183 +194,691 @@ impl Room {
         event: impl Into<String>,
         payload: Value,
     ) {
-        let user_senders = self.user_senders.read().await;
-        let event: String = event.into();
+        self.order_emit(event.into(), payload, true, false, Some(emiter));
+    }
 
-        match emiter {
-            protocol::Emiter::User(user_id) => {
-                for (id, sender) in user_senders.iter() {
-                    if *id == user_id {
-                        continue;
-                    }
+    pub async fn broadcast_to_users(&self, event: impl Into<String>, payload: Value) {
+        self.order_emit(event.into(), payload, true, false, None);
+    }
 
-                    let _ = sender.send(protocol::User::Event(event.clone(), payload.clone()));
-                }
-            }
+    pub async fn emit_to_rooms(
+        &self,
+        emiter: protocol::Emiter,
+        event: impl Into<String>,
+        payload: Value,
+    ) {
+        self.order_emit(event.into(), payload, false, true, Some(emiter));
+    }
+
+    pub async fn broadcast_to_rooms(&self, event: impl Into<String>, payload: Value) {
+        self.order_emit(event.into(), payload, false, true, None);
+    }
+
+    pub async fn emit(&self, emiter: protocol::Emiter, event: impl Into<String>, payload: Value) {
+        self.order_emit(event.into(), payload, true, true, Some(emiter));
+    }
+
+    pub async fn broadcast(&self, event: impl Into<String>, payload: Value) {
+        self.order_emit(event.into(), payload, true, true, None);
+    }
+
+    //Sending is left to the run loop, so emiting never waits for the members
+    fn order_emit(
+        &self,
+        event: String,
+        payload: Value,
+        users: bool,
+        rooms: bool,
+        except: Option<protocol::Emiter>,
+    ) {
+        let order = Order::Emit {
+            event,
+            payload,
+            users,
+            rooms,
+            except,
+        };
+
+        if self.order(order).is_err() {
+            debug!(room = %self.namespace, "Dropped an event emited after the room stopped");
+        }
+    }
+
+    //Waits for the run loop to answer, RoomClosed if it stopped
+    async fn ask<T>(&self, order: impl FnOnce(oneshot::Sender<T>) -> Order) -> Result<T> {
+        let (reply, answer) = oneshot::channel::<T>();
+        let _ = self.order(order(reply));
+
+        answer
+            .await
+            .map_err(|_| Error::RoomClosed(self.namespace.clone()))
+    }
+
+    fn order(&self, order: Order) -> std::result::Result<(), ()> {
+        let order = protocol::Room::Order(protocol::Order(order));
+        self.sender.send(order).map_err(|_| ())
+    }
+
+    fn publish(&self, relay: Relay) {
+        if let Some(adapter) = &self.adapter {
+            adapter.publish(relay);
+        }
+    }
+
+    fn relay_event(&self, event: &str, payload: &Value, except: Option<Uuid>) {
+        if self.adapter.is_some() {
+            self.publish(Relay::Event {
+                room: self.namespace.clone(),
+                event: event.to_string(),
+                payload: payload.clone(),
+                except,
+            });
+        }
+    }
+
+    //Passes the relays for this room into its channel, so they are handled in order by the run loop
+    fn subscribe_relays(&self) {
+        let adapter = match &self.adapter {
+            Some(adapter) => adapter,
+            None => return,
+        };
+
+        let mut relays = adapter.subscribe(&self.namespace);
+        let sender = self.sender.clone();
 
-            protocol::Emiter::Room(_) => {
-                for (_, sender) in user_senders.iter() {
-                    let _ = sender.send(protocol::User::Event(event.clone(), payload.clone()));
+        tokio::spawn(async move {
+            while let Some(relay) = relays.recv().await {
+                if sender.send(protocol::Room::Relayed(relay)).is_err() {
+                    break;
                 }
             }
+        });
+    }
+
+    //The message every user gets for an event, numbered when the history records it.
+    //Only the run loop calls it, so a joining user can't miss or repeat it
+    fn user_command(&self, event: &str, payload: &Value) -> protocol::User {
+        let seq = self
+            .history
+            .as_ref()
+            .and_then(|history| history.record(event, payload));
+
+        match seq {
+            Some(seq) => protocol::User::Recorded(
+                event.to_string(),
+                payload.clone(),
+                self.namespace.clone(),
+                seq,
+            ),
+
+            None => protocol::User::Event(event.to_string(), payload.clone()),
         }
     }
 
-    pub async fn broadcast_to_users(&self, event: impl Into<String>, payload: Value) {
-        let user_senders = self.user_senders.read().await;
-        let event: String = event.into();
+    fn recorded(&self, entry: Entry) -> protocol::User {
+        protocol::User::Recorded(
+            entry.event,
+            entry.payload,
+            self.namespace.clone(),
+            entry.seq,
+        )
+    }
 
-        for (_, sender) in user_senders.iter() {
-            let _ = sender.send(protocol::User::Event(event.clone(), payload.clone()));
+    fn send_to_user(
+        &self,
+        id: &Uuid,
+        sender: &UnboundedSender<protocol::User>,
+        command: protocol::User,
+    ) {
+        if sender.send(command).is_err() {
+            debug!(room = %self.namespace, user = %id, "Dropped message to a disconnected user");
         }
     }
 
-    pub async fn emit_to_rooms(
+    fn send_to_room(
         &self,
-        emiter: protocol::Emiter,
-        event: impl Into<String>,
-        payload: Value,
+        name: &str,
+        sender: &UnboundedSender<protocol::Room>,
+        command: protocol::Room,
     ) {
-        let event: String = event.into();
-        let room_senders = self.room_senders.read().await;
+        if sender.send(command).is_err() {
+            debug!(room = %self.namespace, to = %name, "Dropped message to a closed room");
+        }
+    }
 
-        let new_emiter = protocol::Emiter::Room(self.namespace.clone());
-        let room_command = protocol::Room::Event(event, payload, new_emiter);
+    fn count_sent(&self, event: &str, amount: usize) {
+        metrics()
+            .messages_out
+            .with_label_values(&[self.namespace.as_str(), event])
+            .inc_by(amount as u64);
+    }
 
-        match emiter {
-            protocol::Emiter::User(_) => {
-                for (_, sender) in room_senders.iter() {
-                    let _ = sender.send(room_command.clone());
+    fn count_members(&self, members: usize) {
+        metrics()
+            .room_members
+            .with_label_values(&[self.namespace.as_str()])
+            .set(members as i64);
+    }
+
+    fn get_event(&self, event_name: &str) -> Option<&Event> {
+        self.events.get(event_name)
+    }
+
+    async fn restore_state(&self) {
+        let state = match &self.state {
+            Some(state) => state,
+            None => return,
+        };
+
+        match state.load(&self.namespace).await {
+            Ok(true) => info!(room = %self.namespace, "State restored"),
+            Ok(false) => {}
+            Err(error) => warn!(room = %self.namespace, %error, "Failed to restore the state"),
+        }
+    }
+
+    async fn save_state(&self) {
+        if let Err(error) = self.snapshot().await {
+            warn!(room = %self.namespace, %error, "Failed to save the state");
+        }
+    }
+
+    //Saves the state every interval until the room stops, it only holds the room while saving
+    fn snapshot_periodically(room: Weak<Room>) {
+        tokio::spawn(async move {
+            loop {
+                let interval = match room.upgrade() {
+                    Some(room) => match &room.state {
+                        Some(state) => state.snapshot_interval,
+                        None => return,
+                    },
+                    None => return,
+                };
+
+                time::sleep(interval).await;
+
+                match room.upgrade() {
+                    Some(room) if !room.sender.is_closed() => room.save_state().await,
+                    _ => return,
                 }
             }
+        });
+    }
 
-            protocol::Emiter::Room(room_name) => {
-                for (room_id, sender) in room_senders.iter() {
-                    if *room_id == room_name {
-                        continue;
+    ///Runner////
+    pub fn run(self: &Arc<Room>, mut actor: Actor) -> JoinHandle<()> {
+        let room = self.clone();
+
+        tokio::spawn(async move {
+            if let FanOut::Ring { capacity, .. } = room.fan_out {
+                actor.ring = Some(broadcast::channel(capacity.max(1)).0);
+            }
+
+            //Loading from the store is async and build is not, so the state comes back here,
+            //before the room reads its first message so nothing reaches the handlers ahead of it
+            if room.state.is_some() {
+                room.restore_state().await;
+                Self::snapshot_periodically(Arc::downgrade(&room));
+            }
+
+            room.subscribe_relays();
+
+            if let Some(history) = &room.history {
+                match history.restore().await {
+                    Ok(0) => {}
+                    Ok(restored) => info!(room = %room.namespace, restored, "History restored"),
+                    Err(error) => {
+                        warn!(room = %room.namespace, %error, "Failed to restore the history")
                     }
-                    let _ = sender.send(room_command.clone());
                 }
             }
-        }
-    }
 
-    pub async fn broadcast_to_rooms(&self, event: impl Into<String>, payload: Value) {
-        let event: String = event.into();
-        let room_senders = self.room_senders.read().await;
+            //A sequential handler holds back the next events, everything else is handled in the order it arrives
+            let mut running: Option<JoinHandle<()>> = None;
 
-        let new_emiter = protocol::Emiter::Room(self.namespace.clone());
-        let room_command = protocol::Room::Event(event, payload, new_emiter);
+            //The events that came before the close still run
+            let mut closing = false;
 
-        for (_, sender) in room_senders.iter() {
-            let _ = sender.send(room_command.clone());
-        }
+            loop {
+                if closing && running.is_none() && actor.pending.is_empty() {
+                    break;
+                }
+
+                tokio::select! {
+                    biased;
+
+                    _ = async { running.as_mut().unwrap().await }, if running.is_some() => {
+                        running = actor.next_pending(&room);
+                    }
+
+                    command = actor.receiver.recv() => match command {
+                        Some(protocol::Room::Close) => closing = true,
+                        Some(protocol::Room::Event(..)) if closing => {}
+
+                        Some(protocol::Room::Event(event_name, payload, emiter)) if running.is_some() => {
+                            actor.pending.push_back((event_name, payload, emiter));
+                        }
+
+                        Some(command) => {
+                            if let Some(handler) = actor.command(&room, command) {
+                                running = Some(handler);
+                            }
+                        }
+
+                        None => break,
+                    },
+                }
+            }
+
+            //Closing the receiver lets every holder of the sender know this room stopped
+            actor.receiver.close();
+
+            room.save_state().await;
+        })
     }
+}
 
-    pub async fn emit(&self, emiter: protocol::Emiter, event: impl Into<String>, payload: Value) {
-        let event: String = event.into();
-        let user_senders = self.user_senders.read().await;
-        let room_senders = self.room_senders.read().await;
+impl Actor {
+    //Returns the handler the run loop has to wait for, when the room dispatches sequentially
+    fn command(&mut self, room: &Arc<Room>, command: protocol::Room) -> Option<JoinHandle<()>> {
+        match command {
+            protocol::Room::Event(event_name, payload, emiter) => {
+                return self.call(room, &event_name, payload, emiter);
+            }
 
-        match emiter {
-            protocol::Emiter::User(user_id) => {
-                for (id, sender) in user_senders.iter() {
-                    if *id == user_id {
-                        continue;
+            protocol::Room::ConnectUser(id, user_sender) => {
+                if let Some(history) = &room.history {
+                    if history.config.replay_on_join {
+                        for entry in history.entries() {
+                            room.send_to_user(&id, &user_sender, room.recorded(entry));
+                        }
                     }
+                }
 
-                    let _ = sender.send(protocol::User::Event(event.clone(), payload.clone()));
+                if let (Some(ring), FanOut::Ring { lag, .. }) = (&self.ring, room.fan_out) {
+                    let subscription =
+                        Subscription::new(room.namespace.clone(), id, lag, ring.subscribe());
+                    room.send_to_user(&id, &user_sender, protocol::User::Subscribe(subscription));
                 }
 
-                for (_, sender) in room_senders.iter() {
-                    let room_command = protocol::Room::Event(
-                        event.clone(),
-                        payload.clone(),
-                        protocol::Emiter::Room(self.namespace.clone()),
-                    );
-                    let _ = sender.send(room_command);
+                self.user_senders.insert(id, user_sender);
+                room.count_members(self.user_senders.len());
+
+                if let Some(adapter) = &room.adapter {
+                    room.publish(Relay::Join {
+                        room: room.namespace.clone(),
+                        user: id,
+                        node: adapter.node(),
+                    });
                 }
+
+                info!(room = %room.namespace, user = %id, "User joined");
             }
 
-            protocol::Emiter::Room(room_name) => {
-                for (room_id, sender) in room_senders.iter() {
-                    if *room_id == room_name {
-                        continue;
-                    }
+            protocol::Room::DisconnectUser(id) => {
+                self.user_senders.remove(&id);
+                room.count_members(self.user_senders.len());
+                self.sender_queues.remove(&protocol::Emiter::User(id));
+
+                room.publish(Relay::Leave {
+                    room: room.namespace.clone(),
+                    user: id,
+                });
+
+                info!(room = %room.namespace, user = %id, "User left");
+            }
+
+            protocol::Room::ConnectRoom(name, room_sender) => {
+                self.connect_room(room, name, room_sender);
+            }
+
+            //A room of this process with the same name stays connected
+            protocol::Room::DisconnectRoom(name) => {
+                if matches!(self.room_senders.get(&name), Some(room_sender) if room_sender.is_closed())
+                {
+                    self.room_senders.remove(&name);
+                    info!(room = %room.namespace, to = %name, "Room disconnected");
+                }
+            }
+
+            protocol::Room::Relayed(relay) => self.receive_relay(room, relay),
+
+            protocol::Room::Order(protocol::Order(order)) => self.order(room, order),
+
+            protocol::Room::History(id, since, limit) => self.send_history(room, id, since, limit),
+
+            //The run loop stops before it gets here
+            protocol::Room::Close => {}
+        }
+
+        None
+    }
 
-                    let room_command = protocol::Room::Event(
-                        event.clone(),
-                        payload.clone(),
-                        protocol::Emiter::Room(self.namespace.clone()),
-                    );
-                    let _ = sender.send(room_command);
+    fn order(&mut self, room: &Room, order: Order) {
+        match order {
+            Order::Emit {
+                event,
+                payload,
+                users,
+                rooms,
+                except,
+            } => {
+                if rooms {
+                    self.emit_to_rooms(room, &event, &payload, except.as_ref());
                 }
 
-                for (_, sender) in user_senders.iter() {
-                    let _ = sender.send(protocol::User::Event(event.clone(), payload.clone()));
+                if users {
+                    self.emit_to_users(room, &event, &payload, except.as_ref());
                 }
             }
+
+            Order::Whisper(emiter, event, payload, reply) => {
+                let _ = reply.send(self.whisper(room, emiter, event, payload));
+            }
+
+            Order::Members(reply) => {
+                let _ = reply.send(self.user_senders.keys().copied().collect());
+            }
+
+            Order::RemoteMembers(reply) => {
+                let _ = reply.send(self.remote_members.clone());
+            }
+
+            Order::ConnectedRooms(reply) => {
+                let _ = reply.send(self.room_senders.keys().cloned().collect());
+            }
         }
     }
 
-    pub async fn broadcast(&self, event: impl Into<String>, payload: Value) {
-        let event: String = event.into();
-        let user_senders = self.user_senders.read().await;
-        let room_senders = self.room_senders.read().await;
+    fn emit_to_users(
+        &self,
+        room: &Room,
+        event: &str,
+        payload: &Value,
+        except: Option<&protocol::Emiter>,
+    ) {
+        let except = match except {
+            Some(protocol::Emiter::User(user_id)) => Some(*user_id),
+            _ => None,
+        };
+
+        let command = room.user_command(event, payload);
+        let sent = self.fan_out(room, command, except);
+
+        room.count_sent(event, sent);
+        room.relay_event(event, payload, except);
+    }
+
+    //Sends the command to every user but one, serialized once for all of them.
+    //A ring room pushes it once into the ring. Returns how many users it went to
+    fn fan_out(&self, room: &Room, command: protocol::User, except: Option<Uuid>) -> usize {
+        let skipped = match except {
+            Some(id) => self.user_senders.contains_key(&id) as usize,
+            None => 0,
+        };
+
+        let recipients = self.user_senders.len() - skipped;
+        if recipients == 0 {
+            return 0;
+        }
+
+        let frame = Frame::encode(command);
+
+        if let Some(ring) = &self.ring {
+            let _ = ring.send(Ringed { frame, except });
+            return recipients;
+        }
+
+        let command = protocol::User::Shared(frame);
+
+        for (id, sender) in self.user_senders.iter() {
+            if Some(*id) == except {
+                continue;
+            }
+
+            room.send_to_user(id, sender, command.clone());
+        }
+
+        recipients
+    }
+
+    fn emit_to_rooms(
+        &self,
+        room: &Room,
+        event: &str,
+        payload: &Value,
+        except: Option<&protocol::Emiter>,
+    ) {
+        let except = match except {
+            Some(protocol::Emiter::Room(room_name)) => Some(room_name),
+            _ => None,
+        };
+
+        for (room_id, sender) in self.room_senders.iter() {
+            if Some(room_id) == except {
+                continue;
+            }
 
-        for (_, sender) in room_senders.iter() {
             let room_command = protocol::Room::Event(
-                event.clone(),
+                event.to_string(),
                 payload.clone(),
-                protocol::Emiter::Room(self.namespace.clone()),
+                protocol::Emiter::Room(room.namespace.clone()),
             );
-            let _ = sender.send(room_command);
+            room.send_to_room(room_id, sender, room_command);
         }
+    }
+
+    fn whisper(
+        &self,
+        room: &Room,
+        emiter: protocol::Emiter,
+        event: String,
+        payload: Value,
+    ) -> Result<()> {
+        match emiter {
+            protocol::Emiter::Room(room_name) => {
+                let room_sender = self
+                    .room_senders
+                    .get(&room_name)
+                    .ok_or_else(|| Error::RoomNotFound(room_name.clone()))?;
+
+                let new_emiter = protocol::Emiter::Room(room.namespace.clone());
+                let room_command = protocol::Room::Event(event, payload, new_emiter);
+                room.send_to_room(&room_name, room_sender, room_command);
+            }
+
+            protocol::Emiter::User(user_id) => match self.user_senders.get(&user_id) {
+                Some(user_sender) => {
+                    room.count_sent(&event, 1);
+                    room.send_to_user(&user_id, user_sender, protocol::User::Event(event, payload));
+                }
+
+                None if self.remote_members.contains_key(&user_id) => {
+                    room.publish(Relay::Whisper {
+                        room: room.namespace.clone(),
+                        user: user_id,
+                        event,
+                        payload,
+                    });
+                }
 
-        for (_, sender) in user_senders.iter() {
-            let _ = sender.send(protocol::User::Event(event.clone(), payload.clone()));
+                None => return Err(Error::UserNotFound(user_id)),
+            },
         }
-    }
 
-    fn get_event(&self, event_name: &str) -> &Event {
-        self.events.get(event_name).unwrap()
+        Ok(())
     }
 
-    fn call(self: &Arc<Room>, event_name: &str, value: Value, emiter: protocol::Emiter) {
-        tokio::spawn(self.get_event(event_name)(self.clone(), value, emiter));
+    fn connect_room(
+        &mut self,
+        room: &Room,
+        name: String,
+        room_sender: UnboundedSender<protocol::Room>,
+    ) {
+        //A room that is still running keeps its name, dropping the new sender ends a link that asked for it
+        if matches!(self.room_senders.get(&name), Some(connected) if !connected.is_closed()) {
+            return warn!(room = %room.namespace, to = %name, "A running room is already connected with this name");
+        }
+
+        info!(room = %room.namespace, to = %name, "Room connected");
+        self.room_senders.insert(name, room_sender);
     }
 
-    ///Runner////
-    pub fn run(self: &Arc<Room>) -> JoinHandle<()> {
-        let room = self.clone();
+    //Delivers what the same room on another process sent, only to the users of this process
+    fn receive_relay(&mut self, room: &Room, relay: Relay) {
+        match relay {
+            //Recorded like the events of this room, with the sequence numbers of this node
+            Relay::Event {
+                event,
+                payload,
+                except,
+                ..
+            } => {
+                let command = room.user_command(&event, &payload);
+                let sent = self.fan_out(room, command, except);
+
+                room.count_sent(&event, sent);
+            }
 
-        tokio::spawn(async move {
-            loop {
-                let mut receiver = room.receiver.lock().await;
+            Relay::Whisper {
+                user,
+                event,
+                payload,
+                ..
+            } => {
+                if let Some(sender) = self.user_senders.get(&user) {
+                    room.count_sent(&event, 1);
+                    room.send_to_user(&user, sender, protocol::User::Event(event, payload));
+                }
+            }
 
-                let received_message = receiver.recv().await;
+            Relay::Join { user, node, .. } => {
+                self.remote_members.insert(user, node);
+            }
 
-                match received_message {
-                    Some(room_command) => match room_command {
-                        protocol::Room::Event(event_name, payload, emiter) => {
-                            room.call(event_name.as_str(), payload, emiter);
-                        }
+            Relay::Leave { user, .. } => {
+                self.remote_members.remove(&user);
+            }
 
-                        protocol::Room::ConnectUser(id, user_sender) => {
-                            let mut user_senders = room.user_senders.write().await;
-                            user_senders.insert(id, user_sender);
-                        }
+            Relay::NodeLeft { node } => {
+                self.remote_members
+                    .retain(|_, member_node| *member_node != node);
+            }
 
-                        protocol::Room::DisconnectUser(id) => {
-                            let mut user_senders = room.user_senders.write().await;
-                            user_senders.remove(&id);
-                        }
+            Relay::NodeJoined { node } => {
+                let adapter = match &room.adapter {
+                    Some(adapter) => adapter,
+                    None => return,
+                };
 
-                        protocol::Room::Close => break,
-                    },
-                    None => break,
+                //This node connected again, the other nodes answer with the users they have now
+                if node == adapter.node() {
+                    self.remote_members.clear();
+                }
+
+                for user in self.user_senders.keys() {
+                    room.publish(Relay::Join {
+                        room: room.namespace.clone(),
+                        user: *user,
+                        node: adapter.node(),
+                    });
                 }
             }
-        })
+        }
+    }
+
+    fn send_history(&self, room: &Room, id: Uuid, since: u64, limit: Option<usize>) {
+        let user_sender = match self.user_senders.get(&id) {
+            Some(user_sender) => user_sender,
+            None => {
+                debug!(room = %room.namespace, user = %id, "History asked by a user that is not a member");
+                return;
+            }
+        };
+
+        let (entries, more, last_seq) = match &room.history {
+            Some(history) => {
+                let limit = limit.unwrap_or(history.config.page_size);
+                let (entries, more) = history.since(since, limit.min(history.config.page_size));
+
+                (entries, more, history.last_seq())
+            }
+
+            None => (Vec::new(), false, 0),
+        };
+
+        let entries: Vec<Value> = entries.into_iter().map(Value::from).collect();
+        let data = json!({
+            "room": room.namespace,
+            "entries": entries,
+            "more": more,
+            "last_seq": last_seq,
+        });
+
+        room.send_to_user(
+            &id,
+            user_sender,
+            protocol::User::Event(protocol::HISTORY_EVENT.to_string(), data),
+        );
+    }
+
+    //Starts the oldest events that waited for a sequential handler, until one of them has a handler to wait for
+    fn next_pending(&mut self, room: &Arc<Room>) -> Option<JoinHandle<()>> {
+        while let Some((event_name, payload, emiter)) = self.pending.pop_front() {
+            if let Some(handler) = self.call(room, &event_name, payload, emiter) {
+                return Some(handler);
+            }
+        }
+
+        None
+    }
+
+    fn call(
+        &mut self,
+        room: &Arc<Room>,
+        event_name: &str,
+        value: Value,
+        emiter: protocol::Emiter,
+    ) -> Option<JoinHandle<()>> {
+        let event = match room.get_event(event_name) {
+            Some(event) => event,
+
+            None => {
+                debug!(room = %room.namespace, event = %event_name, "No handler for event");
+                return None;
+            }
+        };
+
+        let span =
+            info_span!("event", room = %room.namespace, event = %event_name, emiter = ?emiter);
+
+        let labels = [room.namespace.as_str(), event_name];
+        metrics().messages_in.with_label_values(&labels).inc();
+
+        let latency = metrics().handler_latency.with_label_values(&labels);
+        let handler = event(room.clone(), value, emiter.clone());
+
+        //Timed from when the handler runs, the time it waits in its sender queue is not its own
+        let handler = async move {
+            let timer = latency.start_timer();
+            handler.await;
+            timer.observe_duration();
+        }
+        .instrument(span);
+
+        match room.dispatch {
+            DispatchMode::Concurrent => {
+                tokio::spawn(handler);
+                None
+            }
+
+            //Spawned so a panicking handler does not take the run loop down, the run loop waits for it
+            DispatchMode::Sequential => Some(tokio::spawn(handler)),
+
+            DispatchMode::PerSender => {
+                self.sender_queues.push(emiter, Box::pin(handler));
+                None
+            }
+        }
     }
 }
@@ -1,23 +1,40 @@
 use crate::{
+    cluster::Adapter,
+    dispatch::DispatchMode,
     event::{BoxFut, EventMap},
+    fanout::FanOut,
+    history::{History, HistoryConfig, HistoryStore},
     protocol,
-    room::Room,
+    room::{Actor, Room},
+    state::{RoomState, StateStore},
 };
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
 use std::{
     collections::HashMap,
     marker::{Send, Sync},
     sync::Arc,
+    time::Duration,
 };
-use tokio::sync::{
-    mpsc::{unbounded_channel, UnboundedSender},
-    Mutex, RwLock,
-};
+use tokio::sync::mpsc::UnboundedSender;
 
 pub struct RoomBuilder {
     namespace: Option<String>,
     events: EventMap,
-    room_senders: RwLock<HashMap<String, UnboundedSender<protocol::Room>>>,
+    room_senders: HashMap<String, UnboundedSender<protocol::Room>>,
+    history: Option<HistoryConfig>,
+    history_store: Option<Arc<dyn HistoryStore>>,
+    state: Option<RoomState>,
+    snapshot_interval: Option<Duration>,
+    adapter: Option<Arc<dyn Adapter>>,
+    dispatch: DispatchMode,
+    fan_out: FanOut,
+}
+
+impl Default for RoomBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RoomBuilder {
@@ -25,7 +42,14 @@ impl RoomBuilder {
         Self {
             namespace: None,
             events: EventMap::new(),
-            room_senders: RwLock::new(HashMap::new()),
+            room_senders: HashMap::new(),
+            history: None,
+            history_store: None,
+            state: None,
+            snapshot_interval: None,
+            adapter: None,
+            dispatch: DispatchMode::default(),
+            fan_out: FanOut::default(),
         }
     }
 
@@ -43,31 +67,78 @@ impl RoomBuilder {
         self
     }
 
-    pub async fn connect_room(self, room: Arc<Room>) -> RoomBuilder {
+    //Records the events the room sends to its users so they can be replayed
+    pub fn history(mut self, config: HistoryConfig) -> RoomBuilder {
+        self.history = Some(config);
+        self
+    }
+
+    //Writes the history to the store too, and loads it back when the room runs again
+    pub fn history_store(mut self, store: Arc<dyn HistoryStore>) -> RoomBuilder {
+        self.history_store = Some(store);
+        self
+    }
+
+    //Gives the room a state that is saved in the store and restored from it when the room runs again
+    pub fn state<T>(mut self, initial: T, store: Arc<dyn StateStore>) -> RoomBuilder
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        self.state = Some(RoomState::new(initial, store));
+        self
+    }
+
+    pub fn snapshot_interval(mut self, interval: Duration) -> RoomBuilder {
+        self.snapshot_interval = Some(interval);
+        self
+    }
+
+    //Shares the events and members of the room with the rooms of the same namespace on other processes
+    pub fn adapter(mut self, adapter: Arc<dyn Adapter>) -> RoomBuilder {
+        self.adapter = Some(adapter);
+        self
+    }
+
+    pub fn dispatch(mut self, mode: DispatchMode) -> RoomBuilder {
+        self.dispatch = mode;
+        self
+    }
+
+    //Rooms with a big audience can fan out through a ring, see FanOut::Ring
+    pub fn fan_out(mut self, fan_out: FanOut) -> RoomBuilder {
+        self.fan_out = fan_out;
+        self
+    }
+
+    pub async fn connect_room(mut self, room: Arc<Room>) -> RoomBuilder {
         self.room_senders
-            .write()
-            .await
             .insert(room.namespace.clone(), room.sender.clone());
 
         self
     }
 
-    pub fn build(self) -> Arc<Room> {
+    //The room, and the actor to give to Room::run
+    pub fn build(self) -> (Arc<Room>, Actor) {
         let namespace = self.namespace.unwrap_or_default();
         let events = self.events;
         let room_senders = self.room_senders;
-        let user_senders = RwLock::new(HashMap::new());
-
-        let (sender, receiver) = unbounded_channel::<protocol::Room>();
-        let receiver = Mutex::new(receiver);
-
-        Arc::new(Room {
-            namespace,
-            events,
-            sender,
-            receiver,
-            user_senders,
-            room_senders,
-        })
+        let mut history = self.history.map(History::new);
+        if let (Some(history), Some(store)) = (&mut history, self.history_store) {
+            history.persist(namespace.clone(), store);
+        }
+
+        let mut state = self.state;
+        if let (Some(state), Some(interval)) = (&mut state, self.snapshot_interval) {
+            state.snapshot_interval = interval;
+        }
+
+        let (mut room, actor) = Room::new(namespace, events, room_senders);
+        room.history = history;
+        room.state = state;
+        room.adapter = self.adapter;
+        room.dispatch = self.dispatch;
+        room.fan_out = self.fan_out;
+
+        (Arc::new(room), actor)
     }
 }
@@ -0,0 +1,269 @@
+use crate::{error::Result, fanout::Subscriptions, protocol, state::BoxFuture};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+    time,
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+//Keeps the messages of parked sessions outside of the process, by session token
+pub trait OfflineQueue: Send + Sync {
+    //Adds the text of a message to the end of the queue of the session
+    fn queue<'a>(&'a self, token: &'a str, message: String) -> BoxFuture<'a, Result<()>>;
+
+    //Removes and returns the messages of the session, oldest first
+    fn take<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Vec<String>>>;
+
+    //Removes the messages queued before this time, whatever session they were for
+    fn purge<'a>(&'a self, before: SystemTime) -> BoxFuture<'a, Result<()>>;
+}
+
+//The receiver of a parked session, and the commands it got that are not messages for the client
+type Spooled = (UnboundedReceiver<protocol::User>, Vec<protocol::User>);
+
+//What a user leaves behind when its connection drops, the rooms keep sending to
+//its channel so everything they send in the meantime waits there
+pub(crate) struct Parked {
+    pub(crate) id: Uuid,
+    pub(crate) connected_rooms: HashMap<String, UnboundedSender<protocol::Room>>,
+    pub(crate) channel_sender: UnboundedSender<protocol::User>,
+
+    //The rings keep their events too, as many as they have room for
+    pub(crate) subscriptions: Subscriptions,
+    parked_at: Instant,
+}
+
+//Where the channel of a parked session is read
+enum Channel {
+    Waiting(UnboundedReceiver<protocol::User>),
+
+    //Into the offline queue, until the session resumes
+    Spooling(oneshot::Sender<()>, JoinHandle<Spooled>),
+}
+
+//Keeps the sessions of disconnected users for a grace window so a client can resume them
+pub struct SessionStore {
+    //How long a session waits for its client before leaving its rooms
+    pub grace: Duration,
+
+    //Where the messages of parked sessions wait, so they outlive the process
+    pub queue: Option<Arc<dyn OfflineQueue>>,
+
+    parked: Mutex<HashMap<String, (Parked, Channel)>>,
+}
+
+impl SessionStore {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            queue: None,
+            parked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    //The messages for the client of a parked session are written to the queue as they arrive
+    //and sent when it resumes. The events of rings stay in the rings
+    pub fn offline_queue(mut self, queue: Arc<dyn OfflineQueue>) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    //Sessions waiting for their client right now
+    pub fn parked(&self) -> usize {
+        self.parked.lock().unwrap().len()
+    }
+
+    pub(crate) fn park(
+        self: &Arc<Self>,
+        token: String,
+        id: Uuid,
+        connected_rooms: HashMap<String, UnboundedSender<protocol::Room>>,
+        channel_sender: UnboundedSender<protocol::User>,
+        channel_receiver: UnboundedReceiver<protocol::User>,
+        subscriptions: Subscriptions,
+    ) {
+        let parked_at = Instant::now();
+
+        let channel = match &self.queue {
+            Some(queue) => {
+                let (stop, stopped) = oneshot::channel();
+                let spooling = spool(token.clone(), queue.clone(), channel_receiver, stopped);
+
+                Channel::Spooling(stop, tokio::spawn(spooling))
+            }
+
+            None => Channel::Waiting(channel_receiver),
+        };
+
+        let parked = Parked {
+            id,
+            connected_rooms,
+            channel_sender,
+            subscriptions,
+            parked_at,
+        };
+
+        self.parked
+            .lock()
+            .unwrap()
+            .insert(token.clone(), (parked, channel));
+        info!(%id, "Session parked");
+
+        let sessions = self.clone();
+
+        tokio::spawn(async move {
+            time::sleep(sessions.grace).await;
+            sessions.expire(&token, parked_at);
+        });
+    }
+
+    //The parked session of the token, with the messages the queue kept for it.
+    //The queue is drained even when the session is gone, say after a restart
+    pub(crate) async fn take(
+        &self,
+        token: &str,
+    ) -> (
+        Option<(Parked, UnboundedReceiver<protocol::User>)>,
+        Vec<String>,
+    ) {
+        let waiting = self.parked.lock().unwrap().remove(token);
+
+        let parked = match waiting {
+            Some((parked, Channel::Waiting(channel_receiver))) => Some((parked, channel_receiver)),
+
+            Some((parked, Channel::Spooling(stop, spooling))) => {
+                let _ = stop.send(());
+
+                match spooling.await {
+                    Ok((channel_receiver, kept)) => {
+                        //Subscriptions and the like are applied once the user runs again
+                        for command in kept {
+                            let _ = parked.channel_sender.send(command);
+                        }
+
+                        Some((parked, channel_receiver))
+                    }
+
+                    //Without its channel the session can't go on
+                    Err(error) => {
+                        warn!(%error, "Lost the channel of a parked session");
+                        None
+                    }
+                }
+            }
+
+            None => None,
+        };
+
+        let queued = match &self.queue {
+            Some(queue) => queue.take(token).await.unwrap_or_else(|error| {
+                warn!(%error, "Failed to read the offline queue");
+                Vec::new()
+            }),
+
+            None => Vec::new(),
+        };
+
+        (parked, queued)
+    }
+
+    //The session may have been resumed and parked again since this timer started
+    fn expire(&self, token: &str, parked_at: Instant) {
+        let waiting = {
+            let mut sessions = self.parked.lock().unwrap();
+
+            match sessions.get(token) {
+                Some((parked, _)) if parked.parked_at == parked_at => sessions.remove(token),
+                _ => None,
+            }
+        };
+
+        let (parked, channel) = match waiting {
+            Some(waiting) => waiting,
+            None => return,
+        };
+
+        for (room_name, room_sender) in &parked.connected_rooms {
+            if room_sender
+                .send(protocol::Room::DisconnectUser(parked.id))
+                .is_err()
+            {
+                debug!(room = %room_name, "Dropped message to a closed room");
+            }
+        }
+
+        //Nobody can resume the session anymore, so what it queued goes too.
+        //Anything older than the grace window was left by sessions a restart lost
+        if let (Some(queue), Channel::Spooling(_, spooling)) = (self.queue.clone(), channel) {
+            let token = token.to_string();
+            let before = SystemTime::now()
+                .checked_sub(self.grace)
+                .unwrap_or(UNIX_EPOCH);
+
+            tokio::spawn(async move {
+                spooling.abort();
+                let _ = spooling.await;
+
+                let forgotten = queue.take(&token).await.map(drop);
+
+                if let Err(error) = forgotten.and(queue.purge(before).await) {
+                    warn!(%error, "Failed to clean the offline queue");
+                }
+            });
+        }
+
+        info!(id = %parked.id, "Session expired");
+    }
+}
+
+//Writes the messages for the client to the queue in the order they arrive, until the session resumes
+async fn spool(
+    token: String,
+    queue: Arc<dyn OfflineQueue>,
+    mut receiver: UnboundedReceiver<protocol::User>,
+    mut stop: oneshot::Receiver<()>,
+) -> Spooled {
+    let mut kept = Vec::new();
+
+    loop {
+        let command = tokio::select! {
+            _ = &mut stop => break,
+
+            command = receiver.recv() => match command {
+                Some(command) => command,
+                None => break,
+            },
+        };
+
+        let text = match &command {
+            protocol::User::Event(..) | protocol::User::Recorded(..) => {
+                Value::from(command.clone()).to_string()
+            }
+
+            protocol::User::Shared(frame) => frame.text().to_string(),
+
+            _ => {
+                kept.push(command);
+                continue;
+            }
+        };
+
+        //The client still gets it from the channel, after the queued ones
+        if let Err(error) = queue.queue(&token, text).await {
+            warn!(%error, "Failed to queue a message of a parked session");
+            kept.push(command);
+        }
+    }
+
+    (receiver, kept)
+}
@@ -0,0 +1,351 @@
+use crate::{
+    error::Result,
+    history::{Entry, HistoryStore},
+    session::OfflineQueue,
+    state::{BoxFuture, StateStore},
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::task;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS room_state (
+        namespace TEXT PRIMARY KEY,
+        state TEXT NOT NULL,
+        saved_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS room_history (
+        namespace TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        event TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        recorded_at INTEGER NOT NULL,
+        PRIMARY KEY (namespace, seq)
+    );
+
+    CREATE TABLE IF NOT EXISTS offline_queue (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        token TEXT NOT NULL,
+        message TEXT NOT NULL,
+        queued_at INTEGER NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS offline_queue_token ON offline_queue (token, id);
+    CREATE INDEX IF NOT EXISTS offline_queue_queued_at ON offline_queue (queued_at);
+";
+
+//Room state, history and the offline queue of parked sessions in a single sqlite file.
+//Every query runs on the blocking thread pool so the run loops of the rooms never wait on the disk
+#[derive(Clone)]
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let connection = blocking(move || Ok(Connection::open(path)?)).await?;
+
+        Self::init(connection).await
+    }
+
+    //A database that lives as long as the store, mostly useful for tests
+    pub async fn in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?).await
+    }
+
+    async fn init(connection: Connection) -> Result<Self> {
+        let store = Self {
+            connection: Arc::new(Mutex::new(connection)),
+        };
+
+        //With the write ahead log a crash in the middle of a write leaves the last commit intact
+        store
+            .run(|connection| {
+                connection.pragma_update(None, "journal_mode", "WAL")?;
+                connection.pragma_update(None, "synchronous", "NORMAL")?;
+                connection.execute_batch(SCHEMA)?;
+                Ok(())
+            })
+            .await?;
+
+        Ok(store)
+    }
+
+    async fn run<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let connection = self.connection.clone();
+        blocking(move || query(&mut connection.lock().unwrap())).await
+    }
+}
+
+impl StateStore for SqliteStore {
+    fn load<'a>(&'a self, namespace: &'a str) -> BoxFuture<'a, Result<Option<Value>>> {
+        let namespace = namespace.to_string();
+
+        Box::pin(self.run(move |connection| {
+            let state: Option<String> = connection
+                .query_row(
+                    "SELECT state FROM room_state WHERE namespace = ?1",
+                    params![namespace],
+                    |row| row.get(0),
+                )
+                .optional()?;
+
+            match state {
+                Some(state) => Ok(Some(serde_json::from_str(&state)?)),
+                None => Ok(None),
+            }
+        }))
+    }
+
+    fn save<'a>(&'a self, namespace: &'a str, state: Value) -> BoxFuture<'a, Result<()>> {
+        let namespace = namespace.to_string();
+        let state = state.to_string();
+
+        Box::pin(self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO room_state (namespace, state, saved_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (namespace) DO UPDATE SET state = excluded.state, saved_at = excluded.saved_at",
+                params![namespace, state, millis(SystemTime::now())],
+            )?;
+            Ok(())
+        }))
+    }
+}
+
+impl HistoryStore for SqliteStore {
+    fn append<'a>(
+        &'a self,
+        namespace: &'a str,
+        entry: Entry,
+        keep: Option<usize>,
+    ) -> BoxFuture<'a, Result<()>> {
+        let namespace = namespace.to_string();
+
+        Box::pin(self.run(move |connection| {
+            let transaction = connection.transaction()?;
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO room_history (namespace, seq, event, payload, recorded_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    namespace,
+                    entry.seq as i64,
+                    entry.event,
+                    entry.payload.to_string(),
+                    millis(entry.time)
+                ],
+            )?;
+
+            if let Some(keep) = keep {
+                let oldest_kept = entry.seq.saturating_sub(keep as u64);
+                transaction.execute(
+                    "DELETE FROM room_history WHERE namespace = ?1 AND seq <= ?2",
+                    params![namespace, oldest_kept as i64],
+                )?;
+            }
+
+            transaction.commit()?;
+            Ok(())
+        }))
+    }
+
+    fn recent<'a>(&'a self, namespace: &'a str, limit: usize) -> BoxFuture<'a, Result<Vec<Entry>>> {
+        let namespace = namespace.to_string();
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+
+        Box::pin(self.run(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT seq, event, payload, recorded_at FROM room_history
+                 WHERE namespace = ?1 ORDER BY seq DESC LIMIT ?2",
+            )?;
+
+            let rows = statement.query_map(params![namespace, limit], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            })?;
+
+            let mut entries = Vec::new();
+            for row in rows {
+                let (seq, event, payload, recorded_at) = row?;
+                let time = UNIX_EPOCH + Duration::from_millis(recorded_at as u64);
+
+                entries.push(Entry::new(
+                    seq as u64,
+                    event,
+                    serde_json::from_str(&payload)?,
+                    time,
+                ));
+            }
+
+            entries.reverse();
+            Ok(entries)
+        }))
+    }
+}
+
+impl OfflineQueue for SqliteStore {
+    fn queue<'a>(&'a self, token: &'a str, message: String) -> BoxFuture<'a, Result<()>> {
+        let token = token.to_string();
+
+        Box::pin(self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO offline_queue (token, message, queued_at) VALUES (?1, ?2, ?3)",
+                params![token, message, millis(SystemTime::now())],
+            )?;
+            Ok(())
+        }))
+    }
+
+    fn take<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Vec<String>>> {
+        let token = token.to_string();
+
+        Box::pin(self.run(move |connection| {
+            let transaction = connection.transaction()?;
+
+            let messages = {
+                let mut statement = transaction
+                    .prepare("SELECT message FROM offline_queue WHERE token = ?1 ORDER BY id")?;
+
+                let rows = statement.query_map(params![token], |row| row.get::<_, String>(0))?;
+                rows.collect::<rusqlite::Result<Vec<String>>>()?
+            };
+
+            transaction.execute("DELETE FROM offline_queue WHERE token = ?1", params![token])?;
+            transaction.commit()?;
+
+            Ok(messages)
+        }))
+    }
+
+    fn purge<'a>(&'a self, before: SystemTime) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM offline_queue WHERE queued_at < ?1",
+                params![millis(before)],
+            )?;
+            Ok(())
+        }))
+    }
+}
+
+async fn blocking<T: Send + 'static>(
+    query: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    task::spawn_blocking(query)
+        .await
+        .map_err(io::Error::other)?
+}
+
+fn millis(time: SystemTime) -> i64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entry(seq: u64) -> Entry {
+        Entry::new(
+            seq,
+            String::from("message"),
+            json!({ "seq": seq }),
+            SystemTime::now(),
+        )
+    }
+
+    fn seqs(entries: &[Entry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.seq).collect()
+    }
+
+    #[tokio::test]
+    async fn states_round_trip() {
+        let store = SqliteStore::in_memory().await.unwrap();
+
+        assert_eq!(store.load("chat").await.unwrap(), None);
+
+        store.save("chat", json!({"count": 1})).await.unwrap();
+        store.save("chat", json!({"count": 2})).await.unwrap();
+        store.save("other", json!("other")).await.unwrap();
+
+        assert_eq!(store.load("chat").await.unwrap(), Some(json!({"count": 2})));
+        assert_eq!(store.load("other").await.unwrap(), Some(json!("other")));
+    }
+
+    #[tokio::test]
+    async fn history_round_trips_oldest_first() {
+        let store = SqliteStore::in_memory().await.unwrap();
+
+        for seq in 1..=3 {
+            store.append("chat", entry(seq), None).await.unwrap();
+        }
+        store.append("other", entry(1), None).await.unwrap();
+
+        let entries = store.recent("chat", 10).await.unwrap();
+        assert_eq!(seqs(&entries), vec![1, 2, 3]);
+        assert_eq!(entries[2].payload, json!({"seq": 3}));
+
+        assert_eq!(seqs(&store.recent("chat", 2).await.unwrap()), vec![2, 3]);
+        assert_eq!(seqs(&store.recent("other", 10).await.unwrap()), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn appending_keeps_the_newest_entries() {
+        let store = SqliteStore::in_memory().await.unwrap();
+
+        for seq in 1..=5 {
+            store.append("chat", entry(seq), Some(2)).await.unwrap();
+        }
+
+        assert_eq!(seqs(&store.recent("chat", 10).await.unwrap()), vec![4, 5]);
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_taken_once_in_order() {
+        let store = SqliteStore::in_memory().await.unwrap();
+
+        for message in ["first", "second", "third"] {
+            store.queue("token", message.to_string()).await.unwrap();
+        }
+        store.queue("other", String::from("other")).await.unwrap();
+
+        assert_eq!(
+            store.take("token").await.unwrap(),
+            vec!["first", "second", "third"]
+        );
+        assert!(store.take("token").await.unwrap().is_empty());
+        assert_eq!(store.take("other").await.unwrap(), vec!["other"]);
+    }
+
+    #[tokio::test]
+    async fn purging_drops_the_old_messages() {
+        let store = SqliteStore::in_memory().await.unwrap();
+
+        store.queue("old", String::from("old")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let before = SystemTime::now();
+        store.queue("new", String::from("new")).await.unwrap();
+
+        store.purge(before).await.unwrap();
+
+        assert!(store.take("old").await.unwrap().is_empty());
+        assert_eq!(store.take("new").await.unwrap(), vec!["new"]);
+    }
+}
@@ -0,0 +1,395 @@
+use crate::{
+    fanout::{self, Delivery, LagPolicy, Subscriptions},
+    http::Rooms,
+    metrics::metrics,
+    protocol,
+};
+use futures_util::stream;
+use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+use hyper::{
+    body::{Bytes, Frame},
+    header,
+    service::Service,
+    Method, Request, Response, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::{ready, Ready},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::{self, Instant, Interval},
+};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+type Body = BoxBody<Bytes, Infallible>;
+
+//Server-Sent Events endpoint for clients that only listen.
+//A GET on the path with ?rooms=a,b joins those rooms as a regular member and streams
+//every event they send until the client goes away
+#[derive(Clone)]
+pub struct EventStream {
+    pub path: String,
+    pub rooms: Rooms,
+
+    //How often a comment is sent so proxies don't close an idle stream
+    pub keep_alive: Duration,
+
+    chunks: Arc<Chunks>,
+}
+
+impl EventStream {
+    pub fn new(path: &str, rooms: HashMap<String, UnboundedSender<protocol::Room>>) -> Self {
+        Self {
+            path: String::from(path),
+            rooms: Arc::new(rooms),
+            keep_alive: Duration::from_secs(15),
+            chunks: Arc::new(Chunks::default()),
+        }
+    }
+
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn handle<B>(&self, request: Request<B>) -> Response<Body> {
+        if request.method() != Method::GET {
+            return text(StatusCode::METHOD_NOT_ALLOWED, "Only GET is supported");
+        }
+
+        let names = match room_names(&request) {
+            Some(names) => names,
+            None => return text(StatusCode::BAD_REQUEST, "No valid rooms to subscribe to"),
+        };
+
+        let mut subscribed = Vec::new();
+
+        for name in names {
+            match self.rooms.get(&name) {
+                Some(room_sender) if !room_sender.is_closed() => {
+                    subscribed.push((name, room_sender.clone()))
+                }
+
+                Some(_) => {
+                    let message = format!("The room {} is closed", name);
+                    return text(StatusCode::SERVICE_UNAVAILABLE, &message);
+                }
+
+                None => {
+                    let message = format!("The room {} does not exist", name);
+                    return text(StatusCode::NOT_FOUND, &message);
+                }
+            }
+        }
+
+        let listener = Listener::connect(subscribed, self.chunks.clone());
+        let mut keep_alive = time::interval_at(Instant::now() + self.keep_alive, self.keep_alive);
+        keep_alive.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+        let frames = stream::unfold(
+            (listener, keep_alive),
+            |(mut listener, mut keep_alive)| async {
+                let chunk = listener.next_chunk(&mut keep_alive).await?;
+                Some((Ok(Frame::data(chunk)), (listener, keep_alive)))
+            },
+        );
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(BodyExt::boxed(StreamBody::new(frames)))
+            .unwrap()
+    }
+}
+
+//The member registered in the rooms, it leaves them when the client stops reading the stream
+struct Listener {
+    id: Uuid,
+    rooms: Vec<(String, UnboundedSender<protocol::Room>)>,
+    receiver: UnboundedReceiver<protocol::User>,
+    subscriptions: Subscriptions,
+    chunks: Arc<Chunks>,
+}
+
+impl Listener {
+    fn connect(rooms: Vec<(String, UnboundedSender<protocol::Room>)>, chunks: Arc<Chunks>) -> Self {
+        let id = Uuid::new_v4();
+        let (sender, receiver) = unbounded_channel::<protocol::User>();
+
+        for (room_name, room_sender) in &rooms {
+            debug!(room = %room_name, %id, "Event stream joining room");
+            let _ = room_sender.send(protocol::Room::ConnectUser(id, sender.clone()));
+        }
+
+        metrics().connections_accepted.inc();
+        metrics().active_users.inc();
+        info!(%id, "Event stream opened");
+
+        Self {
+            id,
+            rooms,
+            receiver,
+            subscriptions: Subscriptions::default(),
+            chunks,
+        }
+    }
+
+    //Next piece of the stream, None once every room let go of this member
+    async fn next_chunk(&mut self, keep_alive: &mut Interval) -> Option<Bytes> {
+        loop {
+            let chunk = tokio::select! {
+                command = self.receiver.recv() => match command? {
+                    protocol::User::Shared(frame) => self.chunks.get(&frame),
+
+                    protocol::User::Subscribe(subscription) => {
+                        self.subscriptions.insert(subscription);
+                        None
+                    }
+
+                    protocol::User::Close => return None,
+
+                    command => chunk(&command),
+                },
+
+                (room_name, lag, delivery) = self.subscriptions.next() => match delivery {
+                    Delivery::Frame(frame) => self.chunks.get(&frame),
+
+                    Delivery::Lagged(missed) => {
+                        if lag == LagPolicy::Disconnect {
+                            self.leave(&room_name);
+                        }
+
+                        chunk(&fanout::lagged(&room_name, missed, lag))
+                    }
+                },
+
+                _ = keep_alive.tick() => return Some(Bytes::from_static(b": keep-alive\n\n")),
+            };
+
+            if chunk.is_some() {
+                return chunk;
+            }
+        }
+    }
+
+    fn leave(&mut self, room_name: &str) {
+        self.subscriptions.remove(room_name);
+
+        if let Some(index) = self.rooms.iter().position(|(name, _)| name == room_name) {
+            let (_, room_sender) = self.rooms.remove(index);
+            let _ = room_sender.send(protocol::Room::DisconnectUser(self.id));
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        for (room_name, room_sender) in &self.rooms {
+            if room_sender
+                .send(protocol::Room::DisconnectUser(self.id))
+                .is_err()
+            {
+                debug!(room = %room_name, "Dropped message to a closed room");
+            }
+        }
+
+        metrics().active_users.dec();
+        info!(id = %self.id, "Event stream closed");
+    }
+}
+
+//The names are split on the commas of the query before decoding, so an encoded comma stays in the name
+fn room_names<B>(request: &Request<B>) -> Option<Vec<String>> {
+    let names = request
+        .uri()
+        .query()?
+        .split('&')
+        .filter_map(|pair| pair.strip_prefix("rooms="))
+        .flat_map(|rooms| rooms.split(','))
+        .filter(|name| !name.is_empty())
+        .map(percent_decode)
+        .collect::<Option<Vec<String>>>()?;
+
+    if names.is_empty() {
+        return None;
+    }
+
+    Some(names)
+}
+
+//None if an escape is broken or the bytes are not utf8
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.bytes();
+
+    while let Some(byte) = rest.next() {
+        match byte {
+            b'%' => {
+                let high = (rest.next()? as char).to_digit(16)?;
+                let low = (rest.next()? as char).to_digit(16)?;
+                bytes.push((high * 16 + low) as u8);
+            }
+
+            b'+' => bytes.push(b' '),
+
+            byte => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+//A frame goes to many event streams, only the first one encodes it. The frames are kept by
+//address, the weak reference keeps the address from going to another frame while it is here
+#[derive(Default)]
+struct Chunks(Mutex<HashMap<usize, Chunk>>);
+
+type Chunk = (Weak<protocol::Frame>, Option<Bytes>);
+
+impl Chunks {
+    fn get(&self, frame: &Arc<protocol::Frame>) -> Option<Bytes> {
+        let mut chunks = self.0.lock().unwrap();
+        let address = Arc::as_ptr(frame) as usize;
+
+        if let Some((_, chunk)) = chunks.get(&address) {
+            return chunk.clone();
+        }
+
+        //The frames no one holds anymore were sent to every stream
+        chunks.retain(|_, (frame, _)| frame.strong_count() > 0);
+
+        let chunk = chunk(&frame.command);
+        chunks.insert(address, (Arc::downgrade(frame), chunk.clone()));
+        chunk
+    }
+}
+
+//What the stream sends for a command, None for the commands it does not show
+fn chunk(command: &protocol::User) -> Option<Bytes> {
+    match command {
+        //A line break in the name would end the event line and let the rest pass as other fields
+        protocol::User::Event(event_name, _) | protocol::User::Recorded(event_name, ..)
+            if event_name.contains(['\r', '\n']) =>
+        {
+            debug!(event = %event_name.escape_debug(), "Dropped an event whose name has a line break");
+            None
+        }
+
+        protocol::User::Event(event_name, data) => Some(Bytes::from(format!(
+            "event: {}\ndata: {}\n\n",
+            event_name, data
+        ))),
+
+        //The sequence number doubles as the event id, so the browser can tell which events it got
+        protocol::User::Recorded(event_name, data, _, seq) => Some(Bytes::from(format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            seq, event_name, data
+        ))),
+
+        //Joining or leaving rooms is up to the query of the request
+        _ => None,
+    }
+}
+
+fn text(code: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(BodyExt::boxed(Full::new(Bytes::from(body.to_string()))))
+        .unwrap()
+}
+
+impl<B> Service<Request<B>> for EventStream {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Infallible>>;
+
+    fn call(&self, request: Request<B>) -> Self::Future {
+        if request.uri().path() != self.path {
+            return ready(Ok(text(StatusCode::NOT_FOUND, "Not found")));
+        }
+
+        ready(Ok(self.handle(request)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn names(query: &str) -> Option<Vec<String>> {
+        let request = Request::builder()
+            .uri(format!("/events?{}", query))
+            .body(())
+            .unwrap();
+
+        room_names(&request)
+    }
+
+    #[test]
+    fn room_names_are_split_then_decoded() {
+        assert_eq!(
+            names("rooms=a%2Cb,c+d&rooms=e%20f"),
+            Some(vec![
+                String::from("a,b"),
+                String::from("c d"),
+                String::from("e f")
+            ])
+        );
+    }
+
+    #[test]
+    fn broken_escapes_are_refused() {
+        assert_eq!(percent_decode("chat%2"), None);
+        assert_eq!(percent_decode("chat%zz"), None);
+        assert_eq!(percent_decode("%ff%fe"), None);
+        assert_eq!(names("rooms=chat,bad%"), None);
+    }
+
+    #[test]
+    fn empty_queries_have_no_rooms() {
+        assert_eq!(names("rooms=,"), None);
+        assert_eq!(names("other=chat"), None);
+    }
+
+    #[test]
+    fn events_become_event_lines() {
+        let event = protocol::User::Event(String::from("message"), json!({"text": "hi"}));
+
+        assert_eq!(
+            chunk(&event).unwrap(),
+            "event: message\ndata: {\"text\":\"hi\"}\n\n"
+        );
+    }
+
+    #[test]
+    fn event_names_with_line_breaks_are_dropped() {
+        for name in ["message\ndata: injected", "message\r", "\r\nid: 9"] {
+            let event = protocol::User::Event(String::from(name), json!({}));
+            assert_eq!(chunk(&event), None);
+        }
+    }
+
+    #[test]
+    fn a_frame_is_encoded_once_and_forgotten_once_dropped() {
+        let chunks = Chunks::default();
+        let event = protocol::User::Event(String::from("message"), json!(1));
+        let frame = protocol::Frame::encode(event);
+
+        let first = chunks.get(&frame).unwrap();
+        let second = chunks.get(&frame).unwrap();
+        assert_eq!(first.as_ptr(), second.as_ptr());
+
+        drop(frame);
+        let other = protocol::Frame::encode(protocol::User::Event(String::from("other"), json!(2)));
+
+        assert_eq!(chunks.get(&other).unwrap(), "event: other\ndata: 2\n\n");
+        assert_eq!(chunks.0.lock().unwrap().len(), 1);
+    }
+}
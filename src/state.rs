@@ -0,0 +1,260 @@
+use crate::error::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    any::Any,
+    collections::HashMap,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{fs, io::AsyncWriteExt, sync::RwLock};
+use uuid::Uuid;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+//Where the rooms keep their state between runs of the process
+pub trait StateStore: Send + Sync {
+    //The last state saved for the room, None if it never saved one
+    fn load<'a>(&'a self, namespace: &'a str) -> BoxFuture<'a, Result<Option<Value>>>;
+
+    fn save<'a>(&'a self, namespace: &'a str, state: Value) -> BoxFuture<'a, Result<()>>;
+}
+
+//Keeps the states in memory, they survive a room being built again but not the process
+#[derive(Default)]
+pub struct MemoryStore {
+    states: Mutex<HashMap<String, Value>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StateStore for MemoryStore {
+    fn load<'a>(&'a self, namespace: &'a str) -> BoxFuture<'a, Result<Option<Value>>> {
+        let state = self.states.lock().unwrap().get(namespace).cloned();
+        Box::pin(async move { Ok(state) })
+    }
+
+    fn save<'a>(&'a self, namespace: &'a str, state: Value) -> BoxFuture<'a, Result<()>> {
+        self.states
+            .lock()
+            .unwrap()
+            .insert(namespace.to_string(), state);
+
+        Box::pin(async { Ok(()) })
+    }
+}
+
+//Keeps every state as a json file in a directory, named after the namespace of its room
+pub struct FileStore {
+    pub dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    //Namespaces can have any character, so everything that is not safe in a file name is escaped
+    fn path(&self, namespace: &str) -> PathBuf {
+        let mut name = String::new();
+
+        for byte in namespace.bytes() {
+            match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+                _ => name.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+
+        self.dir.join(format!("{}.json", name))
+    }
+}
+
+impl StateStore for FileStore {
+    fn load<'a>(&'a self, namespace: &'a str) -> BoxFuture<'a, Result<Option<Value>>> {
+        Box::pin(async move {
+            let content = match fs::read(self.path(namespace)).await {
+                Ok(content) => content,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(error) => return Err(error.into()),
+            };
+
+            Ok(Some(serde_json::from_slice(&content)?))
+        })
+    }
+
+    //Written to a temporary file first so a crash halfway never leaves a broken state behind.
+    //Every save has its own temporary file, so two saves of the same room never write into the same one
+    fn save<'a>(&'a self, namespace: &'a str, state: Value) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let path = self.path(namespace);
+            let temporary = path.with_extension(format!("json.{}.tmp", Uuid::new_v4()));
+            let content = serde_json::to_vec(&state)?;
+
+            fs::create_dir_all(&self.dir).await?;
+
+            if let Err(error) = replace(&temporary, &path, &content).await {
+                let _ = fs::remove_file(&temporary).await;
+                return Err(error.into());
+            }
+
+            //The rename is only durable once the directory that holds the file is synced
+            #[cfg(unix)]
+            fs::File::open(&self.dir).await?.sync_all().await?;
+
+            Ok(())
+        })
+    }
+}
+
+//The content reaches the disk before the rename, or a crash could leave an empty file in place of the state
+async fn replace(temporary: &Path, path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut file = fs::File::create(temporary).await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    fs::rename(temporary, path).await
+}
+
+type Snapshot = Box<dyn Fn() -> BoxFuture<'static, Result<Value>> + Send + Sync>;
+type Restore = Box<dyn Fn(Value) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+//The typed state of a room, kept behind a lock that handlers get with Room::get_state
+pub struct RoomState {
+    value: Arc<dyn Any + Send + Sync>,
+    snapshot: Snapshot,
+    restore: Restore,
+    pub store: Arc<dyn StateStore>,
+
+    //How often the state is saved while the room runs, it is also saved when the room stops
+    pub snapshot_interval: Duration,
+}
+
+impl RoomState {
+    pub(crate) fn new<T>(initial: T, store: Arc<dyn StateStore>) -> Self
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let value = Arc::new(RwLock::new(initial));
+
+        let snapshot_value = value.clone();
+        let snapshot: Snapshot = Box::new(move || {
+            let value = snapshot_value.clone();
+            Box::pin(async move { Ok(serde_json::to_value(&*value.read().await)?) })
+        });
+
+        let restore_value = value.clone();
+        let restore: Restore = Box::new(move |saved| {
+            let value = restore_value.clone();
+            Box::pin(async move {
+                *value.write().await = serde_json::from_value(saved)?;
+                Ok(())
+            })
+        });
+
+        Self {
+            value,
+            snapshot,
+            restore,
+            store,
+            snapshot_interval: Duration::from_secs(30),
+        }
+    }
+
+    pub(crate) fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<RwLock<T>>> {
+        self.value.clone().downcast::<RwLock<T>>().ok()
+    }
+
+    pub(crate) async fn save(&self, namespace: &str) -> Result<()> {
+        let state = (self.snapshot)().await?;
+        self.store.save(namespace, state).await
+    }
+
+    //Returns false when the store had nothing saved for the room
+    pub(crate) async fn load(&self, namespace: &str) -> Result<bool> {
+        match self.store.load(namespace).await? {
+            Some(saved) => {
+                (self.restore)(saved).await?;
+                Ok(true)
+            }
+
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn memory_states_round_trip() {
+        let store = MemoryStore::new();
+
+        assert_eq!(store.load("chat").await.unwrap(), None);
+
+        store.save("chat", json!({"count": 1})).await.unwrap();
+        store.save("chat", json!({"count": 2})).await.unwrap();
+
+        assert_eq!(store.load("chat").await.unwrap(), Some(json!({"count": 2})));
+        assert_eq!(store.load("other").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn file_states_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path().join("states"));
+
+        assert_eq!(store.load("chat").await.unwrap(), None);
+
+        store.save("chat", json!({"count": 1})).await.unwrap();
+        store.save("chat", json!({"count": 2})).await.unwrap();
+
+        //A new store on the same directory is a new run of the process
+        let store = FileStore::new(dir.path().join("states"));
+        assert_eq!(store.load("chat").await.unwrap(), Some(json!({"count": 2})));
+
+        //Only the state is left, no temporary file
+        let files = std::fs::read_dir(&store.dir).unwrap().count();
+        assert_eq!(files, 1);
+    }
+
+    #[test]
+    fn namespaces_are_escaped_into_file_names() {
+        let store = FileStore::new("/states");
+
+        assert_eq!(
+            store.path("chat-room_1"),
+            Path::new("/states/chat-room_1.json")
+        );
+        assert_eq!(
+            store.path("../etc/passwd"),
+            Path::new("/states/%2E%2E%2Fetc%2Fpasswd.json")
+        );
+        assert_eq!(store.path("a b.c"), Path::new("/states/a%20b%2Ec.json"));
+        assert_eq!(store.path("é"), Path::new("/states/%C3%A9.json"));
+    }
+
+    #[tokio::test]
+    async fn namespaces_that_escape_alike_stay_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path());
+
+        store.save("a/b", json!("slash")).await.unwrap();
+        store.save("a%2Fb", json!("percent")).await.unwrap();
+        store.save("a.b", json!("dot")).await.unwrap();
+
+        assert_eq!(store.load("a/b").await.unwrap(), Some(json!("slash")));
+        assert_eq!(store.load("a%2Fb").await.unwrap(), Some(json!("percent")));
+        assert_eq!(store.load("a.b").await.unwrap(), Some(json!("dot")));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::{
+    fanout::{self, Delivery, LagPolicy, Subscriptions},
+    protocol,
+    room::{Actor, Room},
+};
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+    time,
+};
+use uuid::Uuid;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+//Runs a room without any socket so its handlers can be driven from a test
+pub struct TestRoom {
+    pub room: Arc<Room>,
+    handle: JoinHandle<()>,
+}
+
+impl TestRoom {
+    //Takes what RoomBuilder::build returns
+    pub fn new((room, actor): (Arc<Room>, Actor)) -> Self {
+        let handle = room.run(actor);
+
+        Self { room, handle }
+    }
+
+    //Connects a new fake user to the room, the same way a websocket user joins it
+    pub fn user(&self) -> FakeUser {
+        let id = Uuid::new_v4();
+        let (sender, receiver) = unbounded_channel::<protocol::User>();
+
+        let _ = self
+            .room
+            .sender
+            .send(protocol::Room::ConnectUser(id, sender));
+
+        FakeUser {
+            id,
+            room: self.room.sender.clone(),
+            receiver,
+            subscriptions: Subscriptions::default(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    //Sends an event to the room as if another room emitted it
+    pub fn send_from_room(&self, room_name: &str, event: &str, payload: Value) {
+        let emiter = protocol::Emiter::Room(room_name.to_string());
+        let command = protocol::Room::Event(event.to_string(), payload, emiter);
+
+        let _ = self.room.sender.send(command);
+    }
+
+    pub async fn close(self) {
+        let _ = self.room.sender.send(protocol::Room::Close);
+        let _ = self.handle.await;
+    }
+}
+
+pub struct FakeUser {
+    pub id: Uuid,
+    room: UnboundedSender<protocol::Room>,
+    receiver: UnboundedReceiver<protocol::User>,
+    subscriptions: Subscriptions,
+    timeout: Duration,
+}
+
+impl FakeUser {
+    //How long recv and the expect functions wait for a message
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn send(&self, event: &str, payload: Value) {
+        let emiter = protocol::Emiter::User(self.id);
+        let command = protocol::Room::Event(event.to_string(), payload, emiter);
+
+        let _ = self.room.send(command);
+    }
+
+    //Asks the room for the events it recorded after this sequence number, they arrive in a "roommate:history" event
+    pub fn history(&self, since: u64, limit: Option<usize>) {
+        let _ = self
+            .room
+            .send(protocol::Room::History(self.id, since, limit));
+    }
+
+    //Next message the room sent to this user, None if nothing arrived in time.
+    //Shared frames are unwrapped and rings are read, so it is the same however the room fans out
+    pub async fn recv(&mut self) -> Option<protocol::User> {
+        time::timeout(self.timeout, self.next())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn next(&mut self) -> Option<protocol::User> {
+        loop {
+            let command = tokio::select! {
+                command = self.receiver.recv() => command?.unshare(),
+
+                (room_name, lag, delivery) = self.subscriptions.next() => match delivery {
+                    Delivery::Frame(frame) => return Some(frame.command.clone()),
+                    //Like a websocket user, it leaves the room when its policy says so
+                    Delivery::Lagged(missed) => {
+                        if lag == LagPolicy::Disconnect {
+                            self.subscriptions.remove(&room_name);
+                            let _ = self.room.send(protocol::Room::DisconnectUser(self.id));
+                        }
+
+                        return Some(fanout::lagged(&room_name, missed, lag));
+                    }
+                },
+            };
+
+            match command {
+                protocol::User::Subscribe(subscription) => self.subscriptions.insert(subscription),
+                command => return Some(command),
+            }
+        }
+    }
+
+    //Waits for the next message and panics unless it is this event, returns its payload
+    pub async fn expect_event(&mut self, event: &str) -> Value {
+        match self.recv().await {
+            Some(protocol::User::Event(event_name, payload)) if event_name == event => payload,
+
+            Some(protocol::User::Recorded(event_name, payload, ..)) if event_name == event => {
+                payload
+            }
+
+            Some(other) => panic!("Expected event {:?} but received {:?}", event, other),
+
+            None => panic!("Expected event {:?} but nothing arrived", event),
+        }
+    }
+
+    //Panics if the room sends anything to this user during the timeout
+    pub async fn expect_nothing(&mut self) {
+        if let Some(message) = self.recv().await {
+            panic!("Expected nothing but received {:?}", message);
+        }
+    }
+
+    pub fn leave(self) {
+        let _ = self.room.send(protocol::Room::DisconnectUser(self.id));
+    }
+}
@@ -0,0 +1,203 @@
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName};
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task::JoinHandle,
+    time,
+};
+use tokio_rustls::{
+    client,
+    rustls::{ClientConfig, RootCertStore, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor, TlsConnector,
+};
+use tracing::{info, warn};
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    //PEM file with the certificate chain, leaf first
+    pub cert_path: PathBuf,
+
+    //PEM file with the private key of the certificate
+    pub key_path: PathBuf,
+
+    //How often the files are checked for changes, None disables the polling
+    pub reload_interval: Option<Duration>,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            reload_interval: Some(Duration::from_secs(30)),
+        }
+    }
+
+    pub fn reload_interval(mut self, interval: Option<Duration>) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    fn load(&self) -> io::Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = std::fs::metadata(&self.cert_path).ok()?.modified().ok()?;
+        let key = std::fs::metadata(&self.key_path).ok()?.modified().ok()?;
+
+        Some((cert, key))
+    }
+}
+
+//How a connection to another server checks the certificate it is given
+#[derive(Clone, Debug)]
+pub struct TlsClientConfig {
+    //PEM file with the certificates the one of the server has to be signed by
+    pub ca_path: PathBuf,
+
+    //Name the certificate of the server has to be for
+    pub server_name: String,
+}
+
+impl TlsClientConfig {
+    pub fn new(ca_path: impl Into<PathBuf>, server_name: &str) -> Self {
+        Self {
+            ca_path: ca_path.into(),
+            server_name: String::from(server_name),
+        }
+    }
+
+    pub(crate) async fn connect<S>(&self, stream: S) -> io::Result<client::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut roots = RootCertStore::empty();
+
+        for cert in CertificateDer::pem_file_iter(&self.ca_path)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+        {
+            let cert = cert.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            roots
+                .add(cert)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        }
+
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let server_name = ServerName::try_from(self.server_name.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await
+    }
+}
+
+//Holds the acceptor currently in use, swapped in place whenever the certificate is reloaded
+pub(crate) struct Tls {
+    config: TlsConfig,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl Tls {
+    pub(crate) fn new(config: TlsConfig) -> io::Result<Arc<Self>> {
+        let acceptor = RwLock::new(config.load()?);
+
+        Ok(Arc::new(Self { config, acceptor }))
+    }
+
+    pub(crate) async fn accept<S>(&self, stream: S) -> io::Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let acceptor = self.acceptor.read().unwrap().clone();
+        acceptor.accept(stream).await
+    }
+
+    //A broken certificate on disk keeps the previous one serving
+    fn reload(&self) {
+        match self.config.load() {
+            Ok(acceptor) => {
+                *self.acceptor.write().unwrap() = acceptor;
+                info!(cert = ?self.config.cert_path, "Tls certificate reloaded");
+            }
+
+            Err(error) => warn!(%error, "Tls certificate could not be reloaded"),
+        }
+    }
+
+    //Reloads the certificate on SIGHUP and, if configured, when the files change,
+    //until the returned watch is dropped
+    pub(crate) fn watch(self: &Arc<Self>) -> Watch {
+        let tls = self.clone();
+
+        Watch(tokio::spawn(async move {
+            let mut last_modified = tls.config.modified();
+            let mut poll = time::interval(
+                tls.config
+                    .reload_interval
+                    .unwrap_or(Duration::from_secs(60)),
+            );
+
+            #[cfg(unix)]
+            let mut hangup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+
+            loop {
+                #[cfg(unix)]
+                let hangup_fut = async {
+                    match hangup.as_mut() {
+                        Some(hangup) => hangup.recv().await,
+                        None => std::future::pending().await,
+                    }
+                };
+
+                #[cfg(not(unix))]
+                let hangup_fut = std::future::pending::<Option<()>>();
+
+                tokio::select! {
+                    _ = hangup_fut => tls.reload(),
+
+                    _ = poll.tick(), if tls.config.reload_interval.is_some() => {
+                        let modified = tls.config.modified();
+
+                        if modified != last_modified {
+                            last_modified = modified;
+                            tls.reload();
+                        }
+                    }
+                }
+            }
+        }))
+    }
+}
+
+//The reload task of a listener, it stops together with the listener
+pub(crate) struct Watch(JoinHandle<()>);
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
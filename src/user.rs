@@ -1,24 +1,45 @@
-use crate::protocol::{self, Emiter};
+use crate::{
+    error::{Error, Result},
+    fanout::{self, Delivery, LagPolicy, Subscription, Subscriptions},
+    limits::Limits,
+    metrics::metrics,
+    protocol::{self, Emiter, SESSION_EVENT},
+    session::SessionStore,
+};
 use futures_util::{
     select,
     stream::{SplitSink, SplitStream},
-    FutureExt, SinkExt, StreamExt,
+    FutureExt, Sink, SinkExt, Stream, StreamExt,
+};
+use serde_json::{self, json, Value};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll},
 };
-use serde_json::{self, Value};
-use std::{collections::HashMap, sync::Weak};
 use tokio::{
-    net::TcpStream,
     sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     task::JoinHandle,
 };
-use tokio_tungstenite::WebSocketStream;
-use tungstenite::Message::{self, Binary, Close, Ping, Pong, Text};
+use tracing::{debug, field::display, info, info_span, warn, Instrument, Span};
+use tungstenite::{
+    error::Error as WsError,
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message::{self, Binary, Close, Ping, Pong, Text},
+};
 use uuid::Uuid;
 
-type Sender = SplitSink<WebSocketStream<TcpStream>, Message>;
-type Receiver = SplitStream<WebSocketStream<TcpStream>>;
+//Map of all the rooms in the websocket server with their channel sender
+pub type Rooms = Arc<HashMap<String, UnboundedSender<protocol::Room>>>;
 
-pub struct User {
+type Sender<T> = SplitSink<T, Message>;
+type Receiver<T> = SplitStream<T>;
+
+//A connected client, the transport can be anything that carries websocket messages:
+//a websocket over tcp or tls, a stream upgraded by another http server, an in memory pipe...
+pub struct User<T: Sink<Message>> {
     id: Uuid,
     //Map of all the rooms in the websocket server with their channel sender
     rooms: Weak<HashMap<String, UnboundedSender<protocol::Room>>>,
@@ -30,19 +51,34 @@ pub struct User {
     channel_receiver: UnboundedReceiver<protocol::User>,
     channel_sender: UnboundedSender<protocol::User>,
 
+    //The rings of the rooms that fan out through one
+    subscriptions: Subscriptions,
+
+    //Size and structure limits applied to every message of the client
+    limits: Limits,
+
+    //Where the session is parked if the connection drops, and the token to resume it
+    sessions: Option<Arc<SessionStore>>,
+    token: Option<String>,
+
     //Client sender
-    sender: Sender,
+    sender: Sender<T>,
     //Client receiver
-    receiver: Receiver,
+    receiver: Receiver<T>,
 }
 
-impl User {
+impl<T, E> User<T>
+where
+    T: Stream<Item = Result<Message, WsError>> + Sink<Message, Error = E> + Unpin + Send + 'static,
+    E: Display + Send,
+{
     pub fn new(
-        stream: WebSocketStream<TcpStream>,
+        transport: T,
         rooms: Weak<HashMap<String, UnboundedSender<protocol::Room>>>,
+        limits: Limits,
     ) -> Self {
         let id = Uuid::new_v4();
-        let (sender, receiver) = stream.split();
+        let (sender, receiver) = transport.split();
         let (channel_sender, channel_receiver) = unbounded_channel::<protocol::User>();
         let connected_rooms = HashMap::new();
 
@@ -52,16 +88,41 @@ impl User {
             connected_rooms,
             channel_receiver,
             channel_sender,
+            subscriptions: Subscriptions::default(),
+            limits,
+            sessions: None,
+            token: None,
             sender,
             receiver,
         }
     }
 
+    //Lets the client resume this user after a lost connection, the token is sent in a "roommate:session" event
+    pub fn sessions(mut self, sessions: Arc<SessionStore>) -> Self {
+        self.sessions = Some(sessions);
+        self
+    }
+
     pub fn run(mut self) -> JoinHandle<()> {
+        let span = info_span!("user", id = %self.id);
+
         tokio::spawn(async move {
+            metrics().active_users.inc();
+            info!("User connected");
+
+            if self.sessions.is_some() {
+                let token = Uuid::new_v4().to_string();
+                self.send_session(&token, false).await;
+                self.token = Some(token);
+            }
+
+            //Only a connection that ends without closing leaves a session to resume
+            let mut closed = false;
+
             loop {
                 let receiver_fut = self.receiver.next();
                 let room_input_fut = self.channel_receiver.recv();
+                let ring_fut = self.subscriptions.next();
 
                 select! {
                     //This is the message that come from the client
@@ -74,70 +135,160 @@ impl User {
                                     //If the message is ok return it to use it in message variable
                                     Ok(msg) => msg,
 
+                                    //If the client went over the size limits, tell it why before closing
+                                    Err(WsError::Capacity(error)) => {
+                                        warn!(%error, "Message over the size limits");
+                                        self.close_with(CloseCode::Size, "Message too big").await;
+                                        closed = true;
+                                        break
+                                    }
+
                                     //If not, break the listening loop causing to close the connection with user
-                                    Err(_) => break // I have to change this to be able to disconnect the room connection
+                                    Err(error) => {
+                                        debug!(%error, "Connection failed");
+                                        break
+                                    }
                                 }
                             },
-                            None => continue
+
+                            //The client closed the stream
+                            None => break
                         };
 
-                        let result = self.classify_user_input( message);
+                        let result = self.classify_user_input(&message);
 
                         match result {
-                            Ok(user_protocol) =>{
-
-                                match user_protocol{
-                                    protocol::User::Event(event_name, data) =>{
-                                        Self::send_to_rooms(&self, protocol::Room::Event(event_name, data, Emiter::User(self.id)));
-                                    }
-
-                                    protocol::User::ConnectRoom(room_name) =>{
-                                        Self::connect_room(&mut self, room_name)
-                                    }
+                            Ok(Some(protocol::User::Close)) => {
+                                closed = true;
+                                break
+                            }
 
-                                    protocol::User::DisconnectRoom(room_name) =>{
-                                        Self::disconnect_room(&mut self, room_name)
+                            Ok(Some(user_protocol)) =>{
+                                if let Err(error) = self.handle(user_protocol).await {
+                                    if self.report(error).await {
+                                        closed = true;
+                                        break
                                     }
-
-                                    protocol::User::Close =>{break}
                                 }
                             },
 
-                            Err(user_protocol_error) => self.send_to_user(Err(user_protocol_error)).await,
+                            Ok(None) => {}
+
+                            Err(user_protocol_error) => {
+                                warn!(error = %user_protocol_error, "Protocol error");
+                                let request_id = request_id(&message);
+                                self.send_error(user_protocol_error.into(), request_id).await
+                            }
                         }
                      }
 
                      //This is the message that come from other sources that are connected to this task with the input channel
                      room_input = room_input_fut.fuse() =>{
                         match room_input{
+                            Some(command @ (protocol::User::Event(..) | protocol::User::Recorded(..))) =>{
+                                Self::send_to_user(&mut self, Ok(command)).await;
+                            }
+
+                            Some(protocol::User::Shared(frame)) =>{
+                                self.send_message(frame.message.clone()).await;
+                            }
+
+                            Some(protocol::User::Close) | None => {
+                                closed = true;
+                                break
+                            }
+
                             Some(user_protocol) =>{
-                                match user_protocol{
-                                    protocol::User::Event(event_name, data) =>{
-                                        let _ = Self::send_to_user(&mut self, Ok(protocol::User::Event(event_name, data))).await;
+                                if let Err(error) = self.handle(user_protocol).await {
+                                    if self.report(error).await {
+                                        closed = true;
+                                        break
                                     }
+                                }
+                            }
+                        }
+                     }
 
-                                    protocol::User::ConnectRoom(room_name) =>{
-                                        Self::connect_room(&mut self, room_name)
-                                    }
+                     //This is an event of a room that fans out through a ring
+                     (room_name, lag, delivery) = ring_fut.fuse() =>{
+                        match delivery{
+                            Delivery::Frame(frame) => self.send_message(frame.message.clone()).await,
 
-                                    protocol::User::DisconnectRoom(room_name) =>{
-                                        Self::disconnect_room(&mut self, room_name)
-                                    }
+                            Delivery::Lagged(missed) =>{
+                                warn!(room = %room_name, missed, "Fell behind the ring of the room");
+                                self.send_to_user(Ok(fanout::lagged(&room_name, missed, lag))).await;
 
-                                    protocol::User::Close =>{break}
+                                if lag == LagPolicy::Disconnect {
+                                    let _ = self.disconnect_room(room_name);
                                 }
                             }
-                            None => unimplemented!()
                         }
                      }
                 }
             }
-        })
+
+            match (self.sessions.take(), self.token.take()) {
+                //The rooms keep this user as a member until the session expires
+                (Some(sessions), Some(token)) if !closed => sessions.park(
+                    token,
+                    self.id,
+                    self.connected_rooms,
+                    self.channel_sender,
+                    self.channel_receiver,
+                    self.subscriptions,
+                ),
+
+                //Let every room know this user is gone
+                _ => self.leave_rooms(),
+            }
+
+            metrics().active_users.dec();
+            info!("User disconnected");
+        }.instrument(span))
+    }
+
+    //Applies a command of the client, or of another entity, to this user
+    async fn handle(&mut self, command: protocol::User) -> Result<()> {
+        match command {
+            protocol::User::Event(event_name, data) => {
+                self.send_to_rooms(&event_name, &data);
+                Ok(())
+            }
+
+            protocol::User::ConnectRoom(room_name) => self.connect_room(room_name),
+
+            protocol::User::DisconnectRoom(room_name) => self.disconnect_room(room_name),
+
+            protocol::User::History(room_name, since, limit) => {
+                self.request_history(room_name, since, limit)
+            }
+
+            protocol::User::Resume(token) => self.resume(token).await,
+
+            protocol::User::Subscribe(subscription) => {
+                self.subscribe(subscription);
+                Ok(())
+            }
+
+            //Rooms send these to the client, the run loop forwards them without getting here
+            protocol::User::Recorded(..) | protocol::User::Shared(..) => Ok(()),
+
+            //The run loop stops before getting here
+            protocol::User::Close => Ok(()),
+        }
     }
 
-    async fn send_to_user(&mut self, command_result: Result<protocol::User, protocol::Error>) {
-        let sender = &mut self.sender;
+    //Tells the client what went wrong, returns true when the user can not keep running
+    async fn report(&mut self, error: Error) -> bool {
+        warn!(%error, "Command failed");
+
+        let fatal = matches!(error, Error::ServerStopped);
+        self.send_to_user(Err(error)).await;
 
+        fatal
+    }
+
+    async fn send_to_user(&mut self, command_result: Result<protocol::User>) {
         let message: Value = match command_result {
             Ok(command) => command.into(),
 
@@ -145,79 +296,244 @@ impl User {
         };
 
         let serialize_message = serde_json::to_string(&message).unwrap();
-        let _ = sender.send(Message::text(serialize_message)).await;
+        self.send_text(serialize_message).await;
     }
 
-    fn send_to_rooms(&self, command: protocol::Room) {
-        for (_, room_sender) in &self.connected_rooms {
-            let _ = room_sender.send(command.clone());
+    //Carries the request id of the frame that failed, so the client can tell which request it answers
+    async fn send_error(&mut self, error: Error, request_id: Option<Value>) {
+        let mut message: Value = error.into();
+
+        if let Some(request_id) = request_id {
+            message["request_id"] = request_id;
         }
+
+        self.send_text(message.to_string()).await;
     }
 
-    fn classify_user_input(&mut self, input: Message) -> Result<protocol::User, protocol::Error> {
-        match input {
-            //Handle message if it is text
-            Text(message) => Ok(protocol::User::try_from(message)?),
+    async fn send_text(&mut self, text: String) {
+        self.send_message(Message::text(text)).await;
+    }
 
-            //Handle message if it is binary data
-            Binary(_) => {
-                unimplemented!();
-            }
+    async fn send_message(&mut self, message: Message) {
+        if let Err(error) = self.sender.send(message).await {
+            debug!(%error, "Dropped message to the client");
+        }
+    }
 
-            //Handle message if it is a ping
-            Ping(_) => {
-                unimplemented!();
-            }
+    async fn send_session(&mut self, token: &str, resumed: bool) {
+        let data = json!({"token": token, "resumed": resumed});
+        self.send_to_user(Ok(protocol::User::Event(SESSION_EVENT.to_string(), data)))
+            .await;
+    }
 
-            //Handle message if it is a pong
-            Pong(_) => {
-                unimplemented!();
-            }
+    //Becomes the parked user of the token, the rooms it was in never see it leave
+    async fn resume(&mut self, token: String) -> Result<()> {
+        let sessions = self.sessions.clone().ok_or(Error::SessionNotFound)?;
+        let (parked, queued) = sessions.take(&token).await;
 
-            //Handle close message
-            Close(_) => {
-                unimplemented!();
+        //What the queue kept is sent even when the session is gone, the client has to join again then
+        let (parked, channel_receiver) = match parked {
+            Some(parked) => parked,
+
+            None => {
+                for message in queued {
+                    self.send_text(message).await;
+                }
+
+                return Err(Error::SessionNotFound);
             }
+        };
+
+        //Whatever this connection did before resuming is replaced by the session
+        self.leave_rooms();
+
+        info!(resumed = %parked.id, "Session resumed");
+
+        //The rest of the connection is logged as the resumed user
+        Span::current().record("id", display(parked.id));
+
+        self.id = parked.id;
+        self.connected_rooms = parked.connected_rooms;
+        self.channel_sender = parked.channel_sender;
+        self.channel_receiver = channel_receiver;
+        self.subscriptions = parked.subscriptions;
+        self.token = Some(token.clone());
+
+        //Sent before the buffered messages, the queued ones first as they are the oldest
+        self.send_session(&token, true).await;
+
+        for message in queued {
+            self.send_text(message).await;
         }
+
+        Ok(())
     }
 
-    fn connect_room(&mut self, room_name: String) {
-        let rooms_ref = self
-            .rooms
-            .upgrade()
-            .expect("The server is no longer running");
+    //The room answers straight to this user with a "roommate:history" event
+    fn request_history(&self, room_name: String, since: u64, limit: Option<usize>) -> Result<()> {
+        let room_channel = self
+            .connected_rooms
+            .get(&room_name)
+            .ok_or_else(|| Error::NotInRoom(room_name.clone()))?;
 
-        match rooms_ref.get(&room_name) {
-            Some(room_channel) => {
-                self.connected_rooms.insert(room_name, room_channel.clone());
+        room_channel
+            .send(protocol::Room::History(self.id, since, limit))
+            .map_err(|_| Error::RoomClosed(room_name))
+    }
 
-                let _ = room_channel.send(protocol::Room::ConnectUser(
-                    self.id,
-                    self.channel_sender.clone(),
-                ));
-            }
+    //A subscription that arrives after the user left the room is dropped
+    fn subscribe(&mut self, subscription: Subscription) {
+        if self.connected_rooms.contains_key(&subscription.room) {
+            self.subscriptions.insert(subscription);
+        }
+    }
 
-            None => {
-                unimplemented!();
+    fn leave_rooms(&mut self) {
+        self.subscriptions.clear();
+
+        for (room_name, room_sender) in self.connected_rooms.drain() {
+            if room_sender
+                .send(protocol::Room::DisconnectUser(self.id))
+                .is_err()
+            {
+                debug!(room = %room_name, "Dropped message to a closed room");
             }
         }
     }
 
-    fn disconnect_room(&mut self, room_name: String) {
-        let rooms_ref = self
-            .rooms
-            .upgrade()
-            .expect("The server is no longer running");
+    async fn close_with(&mut self, code: CloseCode, reason: &'static str) {
+        let frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+
+        let _ = self.sender.send(Message::Close(Some(frame))).await;
+    }
 
-        match rooms_ref.get(&room_name) {
-            Some(room_channel) => {
-                self.connected_rooms.remove(&room_name);
-                let _ = room_channel.send(protocol::Room::DisconnectUser(self.id));
+    fn send_to_rooms(&self, event_name: &str, data: &Value) {
+        for (room_name, room_sender) in &self.connected_rooms {
+            let event =
+                protocol::Room::Event(event_name.to_string(), data.clone(), Emiter::User(self.id));
+
+            if room_sender.send(event).is_err() {
+                debug!(room = %room_name, "Dropped message to a closed room");
             }
+        }
+    }
 
-            None => {
-                unimplemented!();
+    fn classify_user_input(
+        &mut self,
+        input: &Message,
+    ) -> Result<Option<protocol::User>, protocol::Error> {
+        match input {
+            //Handle message if it is text
+            Text(message) => protocol::User::parse(message, &self.limits).map(Some),
+
+            //Handle message if it is binary data
+            Binary(_) => Err(protocol::Error::BinaryNotSupported),
+
+            //Pings are answered by tungstenite itself and pongs need no answer
+            Ping(_) | Pong(_) => Ok(None),
+
+            //Raw frames are only made for writing, reading never returns one
+            Message::Frame(_) => Ok(None),
+
+            //Handle close message
+            Close(_) => Ok(Some(protocol::User::Close)),
+        }
+    }
+
+    fn room_channel(&self, room_name: &str) -> Result<UnboundedSender<protocol::Room>> {
+        let rooms_ref = self.rooms.upgrade().ok_or(Error::ServerStopped)?;
+
+        match rooms_ref.get(room_name) {
+            Some(room_channel) if room_channel.is_closed() => {
+                Err(Error::RoomClosed(room_name.to_string()))
             }
+
+            Some(room_channel) => Ok(room_channel.clone()),
+
+            None => Err(Error::RoomNotFound(room_name.to_string())),
         }
     }
+
+    fn connect_room(&mut self, room_name: String) -> Result<()> {
+        let room_channel = self.room_channel(&room_name)?;
+
+        debug!(room = %room_name, "Joining room");
+        let _ = room_channel.send(protocol::Room::ConnectUser(
+            self.id,
+            self.channel_sender.clone(),
+        ));
+        self.connected_rooms.insert(room_name, room_channel);
+
+        Ok(())
+    }
+
+    fn disconnect_room(&mut self, room_name: String) -> Result<()> {
+        self.connected_rooms.remove(&room_name);
+        self.subscriptions.remove(&room_name);
+        let room_channel = self.room_channel(&room_name)?;
+
+        debug!(room = %room_name, "Leaving room");
+        let _ = room_channel.send(protocol::Room::DisconnectUser(self.id));
+
+        Ok(())
+    }
+}
+
+//The request id a client put in the data of a frame, see Client::request
+fn request_id(message: &Message) -> Option<Value> {
+    let text = message.to_text().ok()?;
+    let mut frame = serde_json::from_str::<Value>(text).ok()?;
+
+    frame["data"].get_mut("request_id").map(Value::take)
+}
+
+//An in memory transport, whatever is sent on the inbound sender reaches the user as if the client
+//sent it, and everything the user sends to the client comes out of the outbound receiver
+pub struct ChannelTransport {
+    incoming: UnboundedReceiver<Message>,
+    outgoing: UnboundedSender<Message>,
+}
+
+impl ChannelTransport {
+    pub fn pair() -> (Self, UnboundedSender<Message>, UnboundedReceiver<Message>) {
+        let (inbound, incoming) = unbounded_channel::<Message>();
+        let (outgoing, outbound) = unbounded_channel::<Message>();
+
+        let transport = Self { incoming, outgoing };
+
+        (transport, inbound, outbound)
+    }
+}
+
+impl Stream for ChannelTransport {
+    type Item = Result<Message, WsError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_recv(cx).map(|message| message.map(Ok))
+    }
+}
+
+impl Sink<Message> for ChannelTransport {
+    type Error = WsError;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> Result<(), WsError> {
+        self.outgoing
+            .send(message)
+            .map_err(|_| WsError::ConnectionClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        Poll::Ready(Ok(()))
+    }
 }
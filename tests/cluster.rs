@@ -0,0 +1,104 @@
+use roommate::cluster::{TcpAdapter, TcpBroker};
+use roommate::prelude::*;
+use roommate::testkit::TestRoom;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+fn node(broker: &TcpBroker) -> TestRoom {
+    let room = RoomBuilder::new()
+        .namespace("chat")
+        .adapter(TcpAdapter::connect(broker.addr))
+        .on("message", |room, payload, emiter| {
+            Box::pin(async move { room.emit(emiter, "message", payload).await })
+        })
+        .build();
+
+    TestRoom::new(room)
+}
+
+//Waits until the room knows this many users on other nodes
+async fn remote_members(test_room: &TestRoom, count: usize) {
+    for _ in 0..100 {
+        if test_room.room.remote_members().await.unwrap().len() == count {
+            return;
+        }
+
+        time::sleep(Duration::from_millis(20)).await;
+    }
+
+    panic!("The room never saw {} users on other nodes", count);
+}
+
+#[tokio::test]
+async fn users_on_different_nodes_see_each_other() {
+    let broker = TcpBroker::bind("127.0.0.1:0").await.unwrap();
+
+    let first = node(&broker);
+    let mut alice = first.user();
+
+    //Bob's node connects after Alice joined, it learns about her from the snapshot
+    let second = node(&broker);
+    let mut bob = second.user();
+
+    remote_members(&first, 1).await;
+    remote_members(&second, 1).await;
+
+    alice.send("message", json!("hi bob"));
+    assert_eq!(bob.expect_event("message").await, json!("hi bob"));
+
+    bob.send("message", json!("hi alice"));
+    assert_eq!(alice.expect_event("message").await, json!("hi alice"));
+
+    alice.expect_nothing().await;
+    bob.expect_nothing().await;
+
+    first.close().await;
+    second.close().await;
+    broker.stop();
+}
+
+#[tokio::test]
+async fn stopping_the_broker_drops_its_nodes() {
+    let broker = TcpBroker::bind("127.0.0.1:0").await.unwrap();
+
+    let mut stream = TcpStream::connect(broker.addr).await.unwrap();
+    let hello = json!({ "node": uuid::Uuid::new_v4() });
+    stream
+        .write_all(format!("{}\n", hello).as_bytes())
+        .await
+        .unwrap();
+
+    broker.stop();
+
+    //The broker sends nothing to a lone node, so the first read sees the connection close
+    let mut buffer = [0; 64];
+    let read = time::timeout(Duration::from_secs(5), stream.read(&mut buffer)).await;
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+}
+
+#[tokio::test]
+async fn the_broker_drops_a_node_with_an_endless_line() {
+    let broker = TcpBroker::bind("127.0.0.1:0").await.unwrap();
+
+    let mut stream = TcpStream::connect(broker.addr).await.unwrap();
+    let chunk = vec![b'a'; 1 << 16];
+
+    //Without a line break the broker stops reading once the line passes its cap
+    let mut written = 0;
+    while written < 4 << 20 {
+        match stream.write_all(&chunk).await {
+            Ok(()) => written += chunk.len(),
+            Err(_) => break,
+        }
+    }
+
+    let mut buffer = [0; 64];
+    let read = time::timeout(Duration::from_secs(5), stream.read(&mut buffer)).await;
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+
+    broker.stop();
+}
@@ -0,0 +1,53 @@
+use roommate::federation::FederationListener;
+use std::{collections::HashMap, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time,
+};
+
+async fn listener(handshake_timeout: Duration) -> u16 {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    FederationListener::new(("127.0.0.1", port), "secret", HashMap::new())
+        .handshake_timeout(handshake_timeout)
+        .listen()
+        .await
+        .unwrap();
+
+    port
+}
+
+#[tokio::test]
+async fn a_link_that_never_says_hello_is_dropped() {
+    let port = listener(Duration::from_millis(100)).await;
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+
+    let mut buffer = [0; 64];
+    let read = time::timeout(Duration::from_secs(5), stream.read(&mut buffer)).await;
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+}
+
+#[tokio::test]
+async fn a_hello_in_time_is_answered() {
+    let port = listener(Duration::from_secs(5)).await;
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+
+    let hello = r#"{"token": "secret", "from": "there", "to": "here"}"#;
+    stream
+        .write_all(format!("{}\n", hello).as_bytes())
+        .await
+        .unwrap();
+
+    let mut welcome = String::new();
+    BufReader::new(stream)
+        .read_line(&mut welcome)
+        .await
+        .unwrap();
+    assert!(welcome.contains(r#""ok":false"#));
+    assert!(welcome.contains("The room here does not exist"));
+}
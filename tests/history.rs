@@ -0,0 +1,87 @@
+use roommate::prelude::*;
+use roommate::protocol::{self, HISTORY_EVENT};
+use roommate::testkit::{FakeUser, TestRoom};
+use serde_json::Value;
+
+fn chat(config: HistoryConfig) -> (Arc<Room>, room::Actor) {
+    RoomBuilder::new()
+        .namespace("chat")
+        .history(config)
+        .on("message", |room, payload, emiter| {
+            Box::pin(async move { room.emit(emiter, "message", payload).await })
+        })
+        .build()
+}
+
+//Waits for an event the history recorded and returns its sequence number with its payload
+async fn expect_recorded(user: &mut FakeUser) -> (u64, Value) {
+    match user.recv().await {
+        Some(protocol::User::Recorded(_, payload, _, seq)) => (seq, payload),
+        other => panic!("Expected a recorded event but received {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn history_is_sent_in_pages() {
+    let test_room = TestRoom::new(chat(HistoryConfig::new().page_size(2)));
+    let mut alice = test_room.user();
+    let bob = test_room.user();
+
+    for count in 1..=3 {
+        bob.send("message", json!({ "count": count }));
+        assert_eq!(expect_recorded(&mut alice).await.0, count);
+    }
+
+    alice.history(0, None);
+    assert_eq!(
+        alice.expect_event(HISTORY_EVENT).await,
+        json!({
+            "room": "chat",
+            "entries": [
+                {"seq": 1, "event": "message", "data": {"count": 1}},
+                {"seq": 2, "event": "message", "data": {"count": 2}},
+            ],
+            "more": true,
+            "last_seq": 3,
+        })
+    );
+
+    alice.history(2, Some(10));
+    assert_eq!(
+        alice.expect_event(HISTORY_EVENT).await,
+        json!({
+            "room": "chat",
+            "entries": [{"seq": 3, "event": "message", "data": {"count": 3}}],
+            "more": false,
+            "last_seq": 3,
+        })
+    );
+
+    test_room.close().await;
+}
+
+#[tokio::test]
+async fn joining_users_get_the_history_replayed() {
+    let test_room = TestRoom::new(chat(HistoryConfig::new().replay_on_join(true)));
+    let bob = test_room.user();
+
+    for count in 1..=3 {
+        bob.send("message", json!({ "count": count }));
+    }
+
+    //Members are answered in order, so the events are recorded once this returns
+    test_room.room.members().await.unwrap();
+
+    let mut carol = test_room.user();
+
+    for count in 1..=3 {
+        assert_eq!(
+            expect_recorded(&mut carol).await,
+            (count, json!({ "count": count }))
+        );
+    }
+
+    carol.expect_nothing().await;
+
+    test_room.close().await;
+}
@@ -0,0 +1,105 @@
+use roommate::prelude::*;
+use roommate::testkit::TestRoom;
+use std::time::Duration;
+
+//A broadcast sent after a user joined reaches it, whatever the room was doing meanwhile
+async fn join_then_broadcast(dispatch: DispatchMode) {
+    let room = RoomBuilder::new()
+        .namespace("chat")
+        .dispatch(dispatch)
+        .on("slow", |_room, _payload, _emiter| {
+            Box::pin(async { tokio::time::sleep(Duration::from_millis(200)).await })
+        })
+        .build();
+
+    let test_room = TestRoom::new(room);
+    let busy = test_room.user();
+    busy.send("slow", json!(null));
+
+    for round in 0..100 {
+        let mut user = test_room.user();
+        test_room.room.broadcast("round", json!(round)).await;
+
+        assert_eq!(user.expect_event("round").await, json!(round));
+    }
+
+    test_room.close().await;
+}
+
+#[tokio::test]
+async fn a_joined_user_gets_the_next_broadcast() {
+    join_then_broadcast(DispatchMode::Concurrent).await;
+}
+
+#[tokio::test]
+async fn a_joined_user_gets_the_next_broadcast_while_a_sequential_handler_runs() {
+    join_then_broadcast(DispatchMode::Sequential).await;
+}
+
+#[tokio::test]
+async fn sequential_events_run_in_order() {
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let handler_seen = seen.clone();
+
+    let room = RoomBuilder::new()
+        .namespace("chat")
+        .dispatch(DispatchMode::Sequential)
+        .on("step", move |_room, payload, _emiter| {
+            let seen = handler_seen.clone();
+
+            Box::pin(async move {
+                let step = payload.as_u64().unwrap();
+                tokio::time::sleep(Duration::from_millis(10 * (5 - step))).await;
+                seen.lock().unwrap().push(step);
+            })
+        })
+        .build();
+
+    let test_room = TestRoom::new(room);
+    let user = test_room.user();
+
+    for step in 0..5 {
+        user.send("step", json!(step));
+    }
+
+    //Closing waits for the events that came before it
+    test_room.close().await;
+
+    assert_eq!(*seen.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+}
+
+#[tokio::test]
+async fn queued_per_sender_events_are_timed_from_when_they_run() {
+    let room = RoomBuilder::new()
+        .namespace("latency")
+        .dispatch(DispatchMode::PerSender)
+        .on("slow", |_room, _payload, _emiter| {
+            Box::pin(async { tokio::time::sleep(Duration::from_millis(100)).await })
+        })
+        .build();
+
+    let test_room = TestRoom::new(room);
+    let user = test_room.user();
+
+    for _ in 0..4 {
+        user.send("slow", json!(null));
+    }
+
+    let latency = roommate::metrics::metrics()
+        .handler_latency
+        .with_label_values(&["latency", "slow"]);
+
+    for _ in 0..100 {
+        if latency.get_sample_count() == 4 {
+            break;
+        }
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    //Counting the time spent in the queue would add up to a second
+    assert_eq!(latency.get_sample_count(), 4);
+    assert!(latency.get_sample_sum() < 0.7);
+
+    test_room.close().await;
+}
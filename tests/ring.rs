@@ -0,0 +1,69 @@
+use roommate::prelude::*;
+use roommate::protocol::LAGGED_EVENT;
+use roommate::testkit::TestRoom;
+
+//A power of two, the ring keeps exactly this many events
+const CAPACITY: usize = 4;
+
+fn ring_room(lag: LagPolicy) -> (Arc<Room>, room::Actor) {
+    RoomBuilder::new()
+        .namespace("ring")
+        .fan_out(FanOut::Ring {
+            capacity: CAPACITY,
+            lag,
+        })
+        .build()
+}
+
+async fn flood(room: &Room, events: usize) {
+    for round in 0..events {
+        room.broadcast("message", json!({ "round": round })).await;
+    }
+
+    //The run loop answers in order, so every event is in the ring once this returns
+    room.members().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_skipping_user_is_told_what_it_missed_and_keeps_reading() {
+    let test_room = TestRoom::new(ring_room(LagPolicy::Skip));
+    let mut alice = test_room.user();
+
+    flood(&test_room.room, 10).await;
+
+    assert_eq!(
+        alice.expect_event(LAGGED_EVENT).await,
+        json!({"room": "ring", "missed": 6, "left": false})
+    );
+
+    for round in 6..10 {
+        assert_eq!(
+            alice.expect_event("message").await,
+            json!({ "round": round })
+        );
+    }
+
+    alice.expect_nothing().await;
+    assert_eq!(test_room.room.members().await.unwrap(), vec![alice.id]);
+
+    test_room.close().await;
+}
+
+#[tokio::test]
+async fn a_disconnecting_user_is_told_what_it_missed_and_leaves() {
+    let test_room = TestRoom::new(ring_room(LagPolicy::Disconnect));
+    let mut alice = test_room.user();
+
+    flood(&test_room.room, 10).await;
+
+    assert_eq!(
+        alice.expect_event(LAGGED_EVENT).await,
+        json!({"room": "ring", "missed": 6, "left": true})
+    );
+
+    //The events left in the ring are not read once the user left
+    alice.expect_nothing().await;
+    assert!(test_room.room.members().await.unwrap().is_empty());
+
+    test_room.close().await;
+}
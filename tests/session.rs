@@ -0,0 +1,287 @@
+use roommate::prelude::*;
+use roommate::protocol::{self, Emiter, RESUME_EVENT, SESSION_EVENT};
+use roommate::session::SessionStore;
+use roommate::user::{ChannelTransport, Rooms, User};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    time,
+};
+use tungstenite::Message;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+//A user over an in memory transport, in a server whose only room is a channel the test reads
+struct Connection {
+    inbound: UnboundedSender<Message>,
+    outbound: UnboundedReceiver<Message>,
+}
+
+impl Connection {
+    fn open(rooms: &Rooms, sessions: &Arc<SessionStore>) -> Self {
+        let (transport, inbound, outbound) = ChannelTransport::pair();
+
+        User::new(transport, Arc::downgrade(rooms), Limits::default())
+            .sessions(sessions.clone())
+            .run();
+
+        Self { inbound, outbound }
+    }
+
+    fn send(&self, frame: Value) {
+        self.inbound.send(Message::text(frame.to_string())).unwrap();
+    }
+
+    async fn recv(&mut self) -> Value {
+        let message = time::timeout(TIMEOUT, self.outbound.recv())
+            .await
+            .expect("Nothing arrived in time")
+            .expect("The user stopped");
+
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    //The token of the session, from the "roommate:session" event
+    async fn session(&mut self, resumed: bool) -> String {
+        let frame = self.recv().await;
+
+        assert_eq!(frame["event"], SESSION_EVENT);
+        assert_eq!(frame["data"]["resumed"], resumed);
+
+        frame["data"]["token"].as_str().unwrap().to_string()
+    }
+
+    //Drops the connection without closing it, as a lost network would
+    fn drop_connection(self) {}
+}
+
+fn server() -> (Rooms, UnboundedReceiver<protocol::Room>) {
+    let (room, commands) = unbounded_channel();
+    let mut rooms = HashMap::new();
+    rooms.insert(String::from("chat"), room);
+
+    (Arc::new(rooms), commands)
+}
+
+async fn next_command(commands: &mut UnboundedReceiver<protocol::Room>) -> protocol::Room {
+    time::timeout(TIMEOUT, commands.recv())
+        .await
+        .expect("The room got nothing in time")
+        .unwrap()
+}
+
+async fn parked(sessions: &SessionStore, count: usize) {
+    for _ in 0..50 {
+        if sessions.parked() == count {
+            return;
+        }
+
+        time::sleep(Duration::from_millis(10)).await;
+    }
+
+    panic!("The store never had {} parked sessions", count);
+}
+
+#[tokio::test]
+async fn a_resumed_connection_keeps_its_user() {
+    let (rooms, mut commands) = server();
+    let sessions = Arc::new(SessionStore::new(Duration::from_secs(10)));
+
+    let mut first = Connection::open(&rooms, &sessions);
+    let token = first.session(false).await;
+    first.send(json!({"event": "connect", "room": "chat"}));
+
+    let (id, user) = match next_command(&mut commands).await {
+        protocol::Room::ConnectUser(id, user) => (id, user),
+        _ => panic!("Expected the user to join"),
+    };
+
+    first.drop_connection();
+    parked(&sessions, 1).await;
+
+    //The room keeps sending while nobody is connected
+    for count in 0..3 {
+        let event = protocol::User::Event(String::from("message"), json!({ "count": count }));
+        user.send(event).unwrap();
+    }
+
+    let mut second = Connection::open(&rooms, &sessions);
+    second.session(false).await;
+    second.send(json!({"event": RESUME_EVENT, "token": token}));
+
+    assert_eq!(second.session(true).await, token);
+
+    for count in 0..3 {
+        let frame = second.recv().await;
+        assert_eq!(frame["event"], "message");
+        assert_eq!(frame["data"]["count"], count);
+    }
+
+    //The room sees the same user go on, it never left nor joined again
+    second.send(json!({"event": "message", "data": "back"}));
+
+    match next_command(&mut commands).await {
+        protocol::Room::Event(event, data, Emiter::User(emiter)) => {
+            assert_eq!(
+                (event.as_str(), data, emiter),
+                ("message", json!("back"), id)
+            );
+        }
+        _ => panic!("Expected the event of the resumed user"),
+    }
+
+    assert!(commands.try_recv().is_err());
+    assert_eq!(sessions.parked(), 0);
+}
+
+#[tokio::test]
+async fn an_expired_session_leaves_its_rooms() {
+    let (rooms, mut commands) = server();
+    let sessions = Arc::new(SessionStore::new(Duration::from_millis(100)));
+
+    let mut connection = Connection::open(&rooms, &sessions);
+    let token = connection.session(false).await;
+    connection.send(json!({"event": "connect", "room": "chat"}));
+
+    let id = match next_command(&mut commands).await {
+        protocol::Room::ConnectUser(id, _) => id,
+        _ => panic!("Expected the user to join"),
+    };
+
+    connection.drop_connection();
+    parked(&sessions, 1).await;
+
+    match next_command(&mut commands).await {
+        protocol::Room::DisconnectUser(left) => assert_eq!(left, id),
+        _ => panic!("Expected the user to leave"),
+    }
+
+    assert_eq!(sessions.parked(), 0);
+
+    //The token is no good anymore
+    let mut late = Connection::open(&rooms, &sessions);
+    late.session(false).await;
+    late.send(json!({"event": RESUME_EVENT, "token": token}));
+
+    assert_eq!(late.recv().await["event"], "error");
+}
+
+#[cfg(feature = "sqlite")]
+mod offline_queue {
+    use super::*;
+    use roommate::session::OfflineQueue;
+    use roommate::sqlite::SqliteStore;
+    use roommate::state::BoxFuture;
+    use std::{sync::Mutex, time::SystemTime};
+
+    //A sqlite queue that also counts what was written to it, so the test knows when the spool caught up
+    struct Counted {
+        store: SqliteStore,
+        queued: Mutex<usize>,
+    }
+
+    impl OfflineQueue for Counted {
+        fn queue<'a>(
+            &'a self,
+            token: &'a str,
+            message: String,
+        ) -> BoxFuture<'a, roommate::Result<()>> {
+            *self.queued.lock().unwrap() += 1;
+            self.store.queue(token, message)
+        }
+
+        fn take<'a>(&'a self, token: &'a str) -> BoxFuture<'a, roommate::Result<Vec<String>>> {
+            self.store.take(token)
+        }
+
+        fn purge<'a>(&'a self, before: SystemTime) -> BoxFuture<'a, roommate::Result<()>> {
+            self.store.purge(before)
+        }
+    }
+
+    async fn queue() -> Arc<Counted> {
+        let store = SqliteStore::in_memory().await.unwrap();
+
+        Arc::new(Counted {
+            store,
+            queued: Mutex::new(0),
+        })
+    }
+
+    //Parks a session that was in the room, then has the room send it three messages
+    async fn parked_with_messages(
+        sessions: &Arc<SessionStore>,
+        queue: &Counted,
+    ) -> (Rooms, String) {
+        let (rooms, mut commands) = server();
+
+        let mut connection = Connection::open(&rooms, sessions);
+        let token = connection.session(false).await;
+        connection.send(json!({"event": "connect", "room": "chat"}));
+
+        let user = match next_command(&mut commands).await {
+            protocol::Room::ConnectUser(_, user) => user,
+            _ => panic!("Expected the user to join"),
+        };
+
+        connection.drop_connection();
+        parked(sessions, 1).await;
+
+        for count in 0..3 {
+            let event = protocol::User::Event(String::from("message"), json!({ "count": count }));
+            user.send(event).unwrap();
+        }
+
+        for _ in 0..50 {
+            if *queue.queued.lock().unwrap() == 3 {
+                return (rooms, token);
+            }
+
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        panic!("The messages never reached the queue");
+    }
+
+    #[tokio::test]
+    async fn a_resumed_session_gets_what_was_queued() {
+        let queue = queue().await;
+        let sessions = SessionStore::new(Duration::from_secs(10)).offline_queue(queue.clone());
+        let sessions = Arc::new(sessions);
+
+        let (rooms, token) = parked_with_messages(&sessions, &queue).await;
+
+        let mut connection = Connection::open(&rooms, &sessions);
+        connection.session(false).await;
+        connection.send(json!({"event": RESUME_EVENT, "token": token}));
+        connection.session(true).await;
+
+        for count in 0..3 {
+            assert_eq!(connection.recv().await["data"]["count"], count);
+        }
+
+        assert!(queue.take(&token).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn queued_messages_outlive_the_session_store() {
+        let queue = queue().await;
+        let sessions = SessionStore::new(Duration::from_secs(10)).offline_queue(queue.clone());
+
+        let (rooms, token) = parked_with_messages(&Arc::new(sessions), &queue).await;
+
+        //A new process does not know the session, but the queue still has its messages
+        let restarted = SessionStore::new(Duration::from_secs(10)).offline_queue(queue.clone());
+        let mut connection = Connection::open(&rooms, &Arc::new(restarted));
+        connection.session(false).await;
+        connection.send(json!({"event": RESUME_EVENT, "token": token}));
+
+        for count in 0..3 {
+            assert_eq!(connection.recv().await["data"]["count"], count);
+        }
+
+        assert_eq!(connection.recv().await["event"], "error");
+        assert!(queue.take(&token).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,59 @@
+use roommate::prelude::*;
+use roommate::testkit::TestRoom;
+
+fn chat() -> (Arc<Room>, room::Actor) {
+    RoomBuilder::new()
+        .namespace("chat")
+        .on("message", |room, payload, emiter| {
+            Box::pin(async move {
+                room.emit(emiter.clone(), "message", payload.clone()).await;
+                let _ = room.whisper(emiter, "sent", payload).await;
+            })
+        })
+        .build()
+}
+
+#[tokio::test]
+async fn an_event_reaches_the_other_members() {
+    let test_room = TestRoom::new(chat());
+    let mut alice = test_room.user();
+    let mut bob = test_room.user();
+
+    alice.send("message", json!({"text": "hello"}));
+
+    assert_eq!(bob.expect_event("message").await, json!({"text": "hello"}));
+    assert_eq!(alice.expect_event("sent").await, json!({"text": "hello"}));
+    alice.expect_nothing().await;
+
+    test_room.close().await;
+}
+
+#[tokio::test]
+async fn a_user_that_left_gets_nothing() {
+    let test_room = TestRoom::new(chat());
+    let mut alice = test_room.user();
+    let bob = test_room.user();
+
+    bob.leave();
+    alice.send("message", json!("anyone?"));
+
+    assert_eq!(alice.expect_event("sent").await, json!("anyone?"));
+    assert_eq!(test_room.room.members().await.unwrap(), vec![alice.id]);
+
+    test_room.close().await;
+}
+
+#[tokio::test]
+async fn an_event_from_a_room_reaches_every_member() {
+    let test_room = TestRoom::new(chat());
+    let mut alice = test_room
+        .user()
+        .with_timeout(std::time::Duration::from_millis(200));
+
+    test_room.send_from_room("lobby", "message", json!("from the lobby"));
+
+    assert_eq!(alice.expect_event("message").await, json!("from the lobby"));
+    alice.expect_nothing().await;
+
+    test_room.close().await;
+}